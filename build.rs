@@ -0,0 +1,205 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs::{
+        read_dir,
+        read_to_string,
+        write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+struct Arg {
+    name: String,
+    type_: &'static str,
+    interface: Option<String>,
+    nullable: bool,
+}
+
+struct Message {
+    name: String,
+    since: u32,
+    destructor: bool,
+    args: Vec<Arg>,
+}
+
+struct Enum {
+    name: String,
+    entries: Vec<(String, u32)>,
+}
+
+struct Interface {
+    name: String,
+    version: u32,
+    requests: Vec<Message>,
+    events: Vec<Message>,
+    enums: Vec<Enum>,
+}
+
+fn find_xml(dir: &Path, out: &mut Vec<PathBuf>) {
+    let mut entries = read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect::<Vec<_>>();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_xml(&path, out);
+        } else if path.extension().and_then(|e| e.to_str()) == Some("xml") {
+            out.push(path);
+        }
+    }
+}
+
+fn parse_uint(text: &str) -> u32 {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).unwrap();
+    }
+    return text.parse().unwrap();
+}
+
+fn parse_message(node: roxmltree::Node) -> Message {
+    let mut args = vec![];
+    for arg in node.children().filter(|n| n.has_tag_name("arg")) {
+        let interface = arg.attribute("interface").map(|x| x.to_string());
+        let type_ = match arg.attribute("type").unwrap() {
+            "int" => "Int",
+            "uint" => "Uint",
+            "fixed" => "Fixed",
+            "string" => "String",
+            "object" => "Object",
+            "new_id" => if interface.is_some() {
+                "NewId"
+            } else {
+                "UntypedNewId"
+            },
+            "array" => "Array",
+            "fd" => "Fd",
+            t => panic!("Unknown arg type {}", t),
+        };
+        args.push(Arg {
+            name: arg.attribute("name").unwrap().to_string(),
+            type_: type_,
+            interface: interface,
+            nullable: arg.attribute("allow-null") == Some("true"),
+        });
+    }
+    return Message {
+        name: node.attribute("name").unwrap().to_string(),
+        since: node.attribute("since").map(parse_uint).unwrap_or(1),
+        destructor: node.attribute("type") == Some("destructor"),
+        args: args,
+    };
+}
+
+fn write_messages(out: &mut String, messages: &[Message]) {
+    out.push_str("&[");
+    for m in messages {
+        write!(
+            out,
+            "Message {{ name: {:?}, since: {}, destructor: {}, args: &[",
+            m.name,
+            m.since,
+            m.destructor
+        ).unwrap();
+        for a in &m.args {
+            write!(
+                out,
+                "ArgSpec {{ name: {:?}, type_: ArgType::{}, interface: {:?}, nullable: {} }}, ",
+                a.name,
+                a.type_,
+                a.interface,
+                a.nullable
+            ).unwrap();
+        }
+        out.push_str("] }, ");
+    }
+    out.push(']');
+}
+
+fn main() {
+    let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("protocols");
+    println!("cargo:rerun-if-changed={}", root.to_str().unwrap());
+
+    // Core protocol first, then everything else in a stable order
+    let core_path = root.join("wayland.xml");
+    let mut paths = vec![];
+    find_xml(&root, &mut paths);
+    paths.retain(|p| *p != core_path);
+    paths.insert(0, core_path);
+    let mut interfaces = BTreeMap::new();
+    for path in paths {
+        let text = read_to_string(&path).unwrap();
+        let doc =
+            roxmltree::Document::parse(
+                &text,
+            ).unwrap_or_else(|e| panic!("Error parsing protocol {}: {}", path.display(), e));
+        for node in doc.root_element().children().filter(|n| n.has_tag_name("interface")) {
+            let mut interface = Interface {
+                name: node.attribute("name").unwrap().to_string(),
+                version: parse_uint(node.attribute("version").unwrap()),
+                requests: vec![],
+                events: vec![],
+                enums: vec![],
+            };
+            for child in node.children() {
+                if child.has_tag_name("request") {
+                    interface.requests.push(parse_message(child));
+                } else if child.has_tag_name("event") {
+                    interface.events.push(parse_message(child));
+                } else if child.has_tag_name("enum") {
+                    interface.enums.push(Enum {
+                        name: child.attribute("name").unwrap().to_string(),
+                        entries: child
+                            .children()
+                            .filter(|n| n.has_tag_name("entry"))
+                            .map(
+                                |n| (n.attribute("name").unwrap().to_string(), parse_uint(n.attribute("value").unwrap())),
+                            )
+                            .collect(),
+                    });
+                }
+            }
+            if interfaces.contains_key(&interface.name) {
+                panic!("Interface {} defined in multiple protocols, latest in {}", interface.name, path.display());
+            }
+            interfaces.insert(interface.name.clone(), interface);
+        }
+    }
+
+    // Generate
+    let mut out = String::new();
+    for interface in interfaces.values() {
+        writeln!(out, "pub mod {} {{", interface.name).unwrap();
+        out.push_str("    use super::*;\n");
+        for (i, m) in interface.requests.iter().enumerate() {
+            writeln!(out, "    pub const REQ_{}: u16 = {};", m.name.to_uppercase(), i).unwrap();
+        }
+        for (i, m) in interface.events.iter().enumerate() {
+            writeln!(out, "    pub const EVT_{}: u16 = {};", m.name.to_uppercase(), i).unwrap();
+        }
+        for e in &interface.enums {
+            for (name, value) in &e.entries {
+                writeln!(out, "    pub const {}_{}: u32 = {};", e.name.to_uppercase(), name.to_uppercase(), value).unwrap();
+            }
+        }
+        write!(
+            out,
+            "    pub static INTERFACE: Interface = Interface {{ name: {:?}, version: {}, requests: ",
+            interface.name,
+            interface.version
+        ).unwrap();
+        write_messages(&mut out, &interface.requests);
+        out.push_str(", events: ");
+        write_messages(&mut out, &interface.events);
+        out.push_str(" };\n}\n");
+    }
+    out.push_str("/// All known interfaces, sorted by name\npub static INTERFACES: &[&Interface] = &[");
+    for interface in interfaces.values() {
+        write!(out, "&{}::INTERFACE, ", interface.name).unwrap();
+    }
+    out.push_str("];\n");
+    write(PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("protocol.rs"), out).unwrap();
+}
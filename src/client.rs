@@ -0,0 +1,179 @@
+use std::{
+    fs::{
+        read,
+        read_link,
+        read_to_string,
+    },
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+/// Who's on the other end of a downstream connection.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    /// Sequential id of the connection, starting at 1
+    pub conn_id: u64,
+    /// The downstream socket path the client connected to
+    pub socket: String,
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Executable path, from `/proc/PID/exe`
+    pub exe: Option<String>,
+    /// Arguments, from `/proc/PID/cmdline`
+    pub cmdline: Option<Vec<String>>,
+    /// Process name, from `/proc/PID/comm`
+    pub comm: Option<String>,
+    /// Cgroup path, from `/proc/PID/cgroup`
+    pub cgroup: Option<String>,
+    /// Flatpak app id or Snap name, if the client is sandboxed by either. Not
+    /// verified; unsandboxed clients can fake it.
+    pub sandbox_app_id: Option<String>,
+}
+
+fn read_cgroup(pid: i32) -> Option<String> {
+    return parse_cgroup(&read_to_string(format!("/proc/{}/cgroup", pid)).ok()?);
+}
+
+/// Pick the cgroup path from `/proc/PID/cgroup`: the unified hierarchy's, or the
+/// first one listed.
+fn parse_cgroup(text: &str) -> Option<String> {
+    let mut first = None;
+    for line in text.lines() {
+        // Format is `id:controllers:path`; the unified (v2) hierarchy has id `0` and no
+        // controllers
+        let mut parts = line.splitn(3, ':');
+        let (Some(id), Some(_), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        if id == "0" {
+            return Some(path.to_string());
+        }
+        if first.is_none() {
+            first = Some(path.to_string());
+        }
+    }
+    return first;
+}
+
+/// Flatpak writes the app id to `/.flatpak-info` in the sandbox, under
+/// `[Application]`.
+fn read_flatpak_app_id(pid: i32) -> Option<String> {
+    return parse_flatpak_info(&read_to_string(format!("/proc/{}/root/.flatpak-info", pid)).ok()?);
+}
+
+fn parse_flatpak_info(text: &str) -> Option<String> {
+    let mut section = "";
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name;
+        } else if section == "Application" {
+            if let Some(name) = line.strip_prefix("name=") {
+                return Some(name.to_string());
+            }
+        }
+    }
+    return None;
+}
+
+/// Derive the sandbox app id from the systemd scope, for when the process root
+/// isn't readable. Flatpak apps run in `app-flatpak-APPID-N.scope` and snaps in
+/// `snap.NAME.APP-UUID.scope`.
+fn cgroup_sandbox_app_id(cgroup: &str) -> Option<String> {
+    for segment in cgroup.rsplit('/') {
+        if let Some(rest) = segment.strip_prefix("app-flatpak-") {
+            let rest = rest.strip_suffix(".scope").unwrap_or(rest);
+            return Some(rest.rsplit_once('-').map(|(id, _)| id).unwrap_or(rest).to_string());
+        }
+        if let Some(rest) = segment.strip_prefix("snap.") {
+            return Some(rest.split('.').next().unwrap_or(rest).to_string());
+        }
+    }
+    return None;
+}
+
+impl ClientInfo {
+    /// Look up the peer of a newly accepted connection. Anything that can't be
+    /// determined (like if the process already exited) is left empty.
+    pub fn collect(conn_id: u64, socket: &Path, stream: &UnixStream) -> Self {
+        let mut out = ClientInfo {
+            conn_id: conn_id,
+            socket: socket.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let Ok(cred) = stream.peer_cred() else {
+            return out;
+        };
+        out.uid = Some(cred.uid);
+        out.gid = Some(cred.gid);
+        let Some(pid) = cred.pid else {
+            return out;
+        };
+        out.pid = Some(pid);
+        out.exe = read_link(format!("/proc/{}/exe", pid)).ok().map(|p| p.to_string_lossy().to_string());
+        out.cmdline = read(format!("/proc/{}/cmdline", pid)).ok().map(|c| {
+            c
+                .split(|b| *b == 0)
+                .filter(|a| !a.is_empty())
+                .map(|a| String::from_utf8_lossy(a).to_string())
+                .collect()
+        });
+        out.comm = read_to_string(format!("/proc/{}/comm", pid)).ok().map(|c| c.trim_end_matches('\n').to_string());
+        out.cgroup = read_cgroup(pid);
+        out.sandbox_app_id =
+            read_flatpak_app_id(pid).or_else(|| out.cgroup.as_deref().and_then(cgroup_sandbox_app_id));
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup() {
+        assert_eq!(
+            parse_cgroup("0::/user.slice/user-1000.slice/app-foo.scope\n").as_deref(),
+            Some("/user.slice/user-1000.slice/app-foo.scope"),
+        );
+
+        // Hybrid setups list v1 controllers too
+        assert_eq!(
+            parse_cgroup("12:pids:/user.slice\n1:name=systemd:/user.slice/a.scope\n0::/user.slice/b.scope\n")
+                .as_deref(),
+            Some("/user.slice/b.scope"),
+        );
+        assert_eq!(parse_cgroup("4:cpu,cpuacct:/x\n2:memory:/y\n").as_deref(), Some("/x"));
+        assert_eq!(parse_cgroup("0::/with:colon\n").as_deref(), Some("/with:colon"));
+        assert_eq!(parse_cgroup("garbage\n"), None);
+        assert_eq!(parse_cgroup(""), None);
+    }
+
+    #[test]
+    fn cgroup_app_id() {
+        assert_eq!(
+            cgroup_sandbox_app_id("/user.slice/app.slice/app-flatpak-org.mozilla.firefox-1234.scope").as_deref(),
+            Some("org.mozilla.firefox"),
+        );
+        assert_eq!(cgroup_sandbox_app_id("/app-flatpak-org.foo.Bar.scope").as_deref(), Some("org.foo.Bar"));
+        assert_eq!(
+            cgroup_sandbox_app_id("/user.slice/snap.firefox.firefox-0c3b1f6e.scope").as_deref(),
+            Some("firefox"),
+        );
+        assert_eq!(cgroup_sandbox_app_id("/user.slice/app-gnome-org.foo-1.scope"), None);
+        assert_eq!(cgroup_sandbox_app_id(""), None);
+    }
+
+    #[test]
+    fn flatpak_info() {
+        let info = "[Application]\nname=org.example.App\nruntime=org.gnome.Platform\n\n[Instance]\nname=other\n";
+        assert_eq!(parse_flatpak_info(info).as_deref(), Some("org.example.App"));
+        assert_eq!(
+            parse_flatpak_info("[Instance]\nname=other\n[Application]\n  name=late  \n").as_deref(),
+            Some("late"),
+        );
+        assert_eq!(parse_flatpak_info("name=outside\n"), None);
+        assert_eq!(parse_flatpak_info("[Runtime]\nname=org.gnome.Platform\n"), None);
+    }
+}
@@ -0,0 +1,213 @@
+use {
+    crate::protocol::{
+        self,
+        Interface,
+    },
+    rustix::fd::OwnedFd,
+    std::mem::replace,
+};
+
+/// Other ways to get at selections, which aren't isolated; clients with an
+/// isolated clipboard don't see them.
+pub const UNISOLATED_INTERFACES: [&str; 3] = [
+    protocol::gtk_primary_selection_device_manager::INTERFACE.name,
+    protocol::zwlr_data_control_manager_v1::INTERFACE.name,
+    protocol::ext_data_control_manager_v1::INTERFACE.name,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionKind {
+    /// `wl_data_device`
+    Clipboard,
+    /// `zwp_primary_selection_device_v1`
+    Primary,
+}
+
+impl SelectionKind {
+    pub const ALL: [SelectionKind; 2] = [SelectionKind::Clipboard, SelectionKind::Primary];
+
+    /// The kind of selection a device, offer, or source interface is for
+    pub fn of(interface: &str) -> Option<Self> {
+        match interface {
+            "wl_data_device_manager" | "wl_data_device" | "wl_data_offer" | "wl_data_source" => {
+                return Some(SelectionKind::Clipboard);
+            },
+            "zwp_primary_selection_device_manager_v1" |
+            "zwp_primary_selection_device_v1" |
+            "zwp_primary_selection_offer_v1" |
+            "zwp_primary_selection_source_v1" => {
+                return Some(SelectionKind::Primary);
+            },
+            _ => return None,
+        }
+    }
+
+    pub fn offer_interface(self) -> &'static Interface {
+        match self {
+            SelectionKind::Clipboard => return &protocol::wl_data_offer::INTERFACE,
+            SelectionKind::Primary => return &protocol::zwp_primary_selection_offer_v1::INTERFACE,
+        }
+    }
+
+    pub fn device_data_offer(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_device::EVT_DATA_OFFER,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_device_v1::EVT_DATA_OFFER,
+        }
+    }
+
+    pub fn device_selection(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_device::EVT_SELECTION,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_device_v1::EVT_SELECTION,
+        }
+    }
+
+    pub fn offer_offer(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_offer::EVT_OFFER,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_offer_v1::EVT_OFFER,
+        }
+    }
+
+    pub fn offer_receive(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_offer::REQ_RECEIVE,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_offer_v1::REQ_RECEIVE,
+        }
+    }
+
+    pub fn offer_destroy(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_offer::REQ_DESTROY,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_offer_v1::REQ_DESTROY,
+        }
+    }
+
+    pub fn source_send(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_source::EVT_SEND,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_source_v1::EVT_SEND,
+        }
+    }
+
+    pub fn source_cancelled(self) -> u16 {
+        match self {
+            SelectionKind::Clipboard => return protocol::wl_data_source::EVT_CANCELLED,
+            SelectionKind::Primary => return protocol::zwp_primary_selection_source_v1::EVT_CANCELLED,
+        }
+    }
+}
+
+/// A selection made by an isolated client.
+#[derive(Debug)]
+pub struct Selection {
+    /// Unique per selection, so offers for old selections can be told apart
+    pub serial: u64,
+    pub conn_id: u64,
+    /// The source object, as an upstream id on the owner's connection
+    pub source: u32,
+    pub mime_types: Vec<String>,
+}
+
+/// A message for one connection from another.
+#[derive(Debug)]
+pub enum Delivery {
+    /// Another client is pasting; the source's client should write the data to `fd`
+    Send {
+        kind: SelectionKind,
+        source: u32,
+        mime_type: String,
+        fd: OwnedFd,
+    },
+    /// The source was replaced as the selection
+    Cancelled {
+        kind: SelectionKind,
+        source: u32,
+    },
+}
+
+/// Selections shared by the isolated clients of this filterway instance.
+/// Connections leave messages for each other here, which the main loop delivers
+/// after each pump.
+pub struct Clipboard {
+    clipboard: Option<Selection>,
+    primary: Option<Selection>,
+    next_serial: u64,
+    /// Kinds whose selection changed, to offer to every isolated client
+    pub changed: Vec<SelectionKind>,
+    /// Messages by connection id
+    pub outbox: Vec<(u64, Delivery)>,
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        return Self {
+            clipboard: None,
+            primary: None,
+            next_serial: 0,
+            changed: vec![],
+            outbox: vec![],
+        };
+    }
+}
+
+impl Clipboard {
+    pub fn get(&self, kind: SelectionKind) -> Option<&Selection> {
+        match kind {
+            SelectionKind::Clipboard => return self.clipboard.as_ref(),
+            SelectionKind::Primary => return self.primary.as_ref(),
+        }
+    }
+
+    fn slot(&mut self, kind: SelectionKind) -> &mut Option<Selection> {
+        match kind {
+            SelectionKind::Clipboard => return &mut self.clipboard,
+            SelectionKind::Primary => return &mut self.primary,
+        }
+    }
+
+    /// Replace the selection with a source (upstream id) on a connection, or clear it.
+    /// The previous source is cancelled.
+    pub fn set(&mut self, kind: SelectionKind, source: Option<(u64, u32, Vec<String>)>) {
+        self.next_serial += 1;
+        let serial = self.next_serial;
+        let new = source.map(|(conn_id, source, mime_types)| Selection {
+            serial: serial,
+            conn_id: conn_id,
+            source: source,
+            mime_types: mime_types,
+        });
+        let new_owner = new.as_ref().map(|s| (s.conn_id, s.source));
+        if let Some(old) = replace(self.slot(kind), new) {
+            if new_owner != Some((old.conn_id, old.source)) {
+                self.outbox.push((old.conn_id, Delivery::Cancelled {
+                    kind: kind,
+                    source: old.source,
+                }));
+            }
+        }
+        self.changed.push(kind);
+    }
+
+    /// Clear the selection if it's from a source that was destroyed.
+    pub fn source_destroyed(&mut self, kind: SelectionKind, conn_id: u64, source: u32) {
+        let slot = self.slot(kind);
+        if slot.as_ref().is_some_and(|s| s.conn_id == conn_id && s.source == source) {
+            *slot = None;
+            self.changed.push(kind);
+        }
+    }
+
+    /// Clear selections from a connection that closed.
+    pub fn connection_closed(&mut self, conn_id: u64) {
+        for kind in SelectionKind::ALL {
+            let slot = self.slot(kind);
+            if slot.as_ref().is_some_and(|s| s.conn_id == conn_id) {
+                *slot = None;
+                self.changed.push(kind);
+            }
+        }
+        self.outbox.retain(|(id, _)| *id != conn_id);
+    }
+}
@@ -0,0 +1,480 @@
+use {
+    crate::{
+        client::ClientInfo,
+        protocol,
+        template::Template,
+    },
+    aargvark::Aargvark,
+    regex::Regex,
+    serde::{
+        Deserialize,
+        Deserializer,
+    },
+    std::path::Path,
+};
+
+/// Longest app id or title (in bytes) filterway sends after rewriting. Templates
+/// can expand to anything, and libwayland disconnects clients whose messages don't
+/// fit its 4096 byte buffer, so rewritten values are cut short well below that.
+pub const MAX_REWRITTEN_LEN: usize = 1024;
+
+/// Truncate a rewritten value to `MAX_REWRITTEN_LEN`, on a character boundary.
+/// Values the client sent unchanged are left alone.
+fn limit_rewritten(original: Option<&str>, out: Option<String>) -> Option<String> {
+    let mut out = out?;
+    if Some(out.as_str()) != original && out.len() > MAX_REWRITTEN_LEN {
+        let mut end = MAX_REWRITTEN_LEN;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.truncate(end);
+    }
+    return Some(out);
+}
+
+/// A regex parsed from a config string.
+#[derive(Clone, Debug)]
+pub struct ConfigRegex(pub Regex);
+
+impl PartialEq for ConfigRegex {
+    fn eq(&self, other: &Self) -> bool {
+        return self.0.as_str() == other.0.as_str();
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        return Ok(ConfigRegex(Regex::new(&text).map_err(serde::de::Error::custom)?));
+    }
+}
+
+/// A shell-style glob parsed from a config string. `*` matches anything
+/// (including `/`) and `?` matches any one character.
+#[derive(Clone, Debug)]
+pub struct ConfigGlob(pub Regex);
+
+impl PartialEq for ConfigGlob {
+    fn eq(&self, other: &Self) -> bool {
+        return self.0.as_str() == other.0.as_str();
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigGlob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let mut pattern = String::from("^");
+        for c in text.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        return Ok(ConfigGlob(Regex::new(&pattern).map_err(serde::de::Error::custom)?));
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Config {
+    /// Rules are applied in order; later actions build on the results of earlier
+    /// ones, but conditions always use the values the client set.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Rule {
+    /// Conditions that must all hold for the rule's actions to apply. An empty match
+    /// applies to everything.
+    #[serde(default, rename = "match")]
+    pub match_: Match,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Match {
+    /// The interface of a global being advertised. Rules with this only apply to
+    /// globals.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// The app id the client set on the toplevel. Rules with this only apply to
+    /// toplevels.
+    #[serde(default)]
+    pub app_id: Option<String>,
+    /// The title the client set on the toplevel. Rules with this only apply to
+    /// toplevels.
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Like `app_id` but matches if the regex matches anywhere in the app id (use `^`
+    /// and `$` to match the whole value)
+    #[serde(default)]
+    pub app_id_regex: Option<ConfigRegex>,
+    /// Like `title` but matches if the regex matches anywhere in the title
+    #[serde(default)]
+    pub title_regex: Option<ConfigRegex>,
+    /// The full path of the client's executable
+    #[serde(default)]
+    pub exe: Option<String>,
+    /// The client's process name
+    #[serde(default)]
+    pub comm: Option<String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// A glob matching the whole cgroup path of the client, like
+    /// `*/app-firefox-*.scope`
+    #[serde(default)]
+    pub cgroup: Option<ConfigGlob>,
+    /// The Flatpak app id or Snap name of the client. This comes from
+    /// `/.flatpak-info` in the client's root or its systemd scope name, both of which
+    /// unsandboxed clients (or a container's root) can fake, so it's not a security
+    /// boundary: don't use it to grant access.
+    #[serde(default)]
+    pub sandbox_app_id: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Replace {
+    pub regex: ConfigRegex,
+    /// Replaces every match of the regex. `$1`, `${name}` etc. refer to capture
+    /// groups.
+    pub with: Template,
+}
+
+/// How isolated clients' clipboards relate to the compositor's.
+#[derive(Aargvark, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipboardIsolation {
+    /// Clients only see selections made by other isolated clients on this filterway
+    /// instance, and their selections stay there
+    Private,
+    /// Like `private`, but clients see the compositor's selections while no isolated
+    /// client has one
+    PasteFromHost,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Action {
+    /// Don't advertise matching globals, and reject binds to them
+    HideGlobal,
+    /// Advertise matching globals even if hidden by an earlier rule
+    ShowGlobal,
+    /// Advertise at most this version of matching globals
+    MaxVersion(u32),
+    /// Advertise at most the version of matching globals in the protocol files
+    /// filterway was built with
+    ClampVersion,
+    SetAppId(Template),
+    PrefixAppId(Template),
+    SuffixAppId(Template),
+    ReplaceAppId(Replace),
+    SetTitle(Template),
+    PrefixTitle(Template),
+    SuffixTitle(Template),
+    ReplaceTitle(Replace),
+    /// Make the compositor draw decorations for matching toplevels, and ignore the
+    /// client's own decoration requests. Decided when the toplevel is created, so
+    /// `app_id` and `title` conditions never match.
+    ForceServerSideDecorations,
+    /// Give matching clients a clipboard and primary selection shared only among
+    /// themselves. Drag and drop isn't affected.
+    IsolateClipboard(ClipboardIsolation),
+}
+
+/// What the client sent for a toplevel, before rewriting.
+#[derive(Clone, Debug, Default)]
+pub struct Toplevel {
+    pub app_id: Option<String>,
+    pub title: Option<String>,
+}
+
+/// How to advertise a global.
+#[derive(Clone, Debug, Default)]
+pub struct GlobalFilter {
+    pub hidden: bool,
+    pub max_version: Option<u32>,
+}
+
+fn match_opt(want: &Option<String>, have: Option<&str>) -> bool {
+    match want {
+        None => return true,
+        Some(want) => return have == Some(want.as_str()),
+    }
+}
+
+fn match_opt_regex(want: &Option<ConfigRegex>, have: Option<&str>) -> bool {
+    match want {
+        None => return true,
+        Some(want) => return have.is_some_and(|have| want.0.is_match(have)),
+    }
+}
+
+impl Match {
+    fn matches_client(&self, client: &ClientInfo) -> bool {
+        return match_opt(&self.exe, client.exe.as_deref()) && match_opt(&self.comm, client.comm.as_deref()) &&
+            self.uid.is_none_or(|uid| client.uid == Some(uid)) &&
+            self.cgroup.as_ref().is_none_or(|want| client.cgroup.as_deref().is_some_and(|have| want.0.is_match(have))) &&
+            match_opt(&self.sandbox_app_id, client.sandbox_app_id.as_deref());
+    }
+
+    fn matches_global(&self, interface: &str, client: &ClientInfo) -> bool {
+        return self.app_id.is_none() && self.title.is_none() && self.app_id_regex.is_none() &&
+            self.title_regex.is_none() &&
+            match_opt(&self.interface, Some(interface)) &&
+            self.matches_client(client);
+    }
+
+    fn matches_toplevel(&self, toplevel: &Toplevel, client: &ClientInfo) -> bool {
+        return self.interface.is_none() && self.matches_client(client) &&
+            match_opt(&self.app_id, toplevel.app_id.as_deref()) &&
+            match_opt(&self.title, toplevel.title.as_deref()) &&
+            match_opt_regex(&self.app_id_regex, toplevel.app_id.as_deref()) &&
+            match_opt_regex(&self.title_regex, toplevel.title.as_deref());
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read(path).map_err(|e| format!("Error reading config at {}: {}", path.display(), e))?;
+        return serde_json::from_slice(
+            &text,
+        ).map_err(|e| format!("Error parsing config at {}: {}", path.display(), e));
+    }
+
+    pub fn global(&self, interface: &str, client: &ClientInfo) -> GlobalFilter {
+        let mut out = GlobalFilter::default();
+        for rule in &self.rules {
+            if !rule.match_.matches_global(interface, client) {
+                continue;
+            }
+            for action in &rule.actions {
+                match action {
+                    Action::HideGlobal => out.hidden = true,
+                    Action::ShowGlobal => out.hidden = false,
+                    Action::MaxVersion(v) => {
+                        out.max_version = Some(out.max_version.map_or(*v, |m| m.min(*v)));
+                    },
+                    Action::ClampVersion => {
+                        if let Some(known) = protocol::find_interface(interface) {
+                            out.max_version = Some(out.max_version.map_or(known.version, |m| m.min(known.version)));
+                        }
+                    },
+                    _ => { },
+                }
+            }
+        }
+        return out;
+    }
+
+    /// Whether to force server-side decorations on a new toplevel.
+    pub fn force_server_side_decorations(&self, client: &ClientInfo) -> bool {
+        let mut out = false;
+        for rule in &self.rules {
+            if !rule.match_.matches_toplevel(&Toplevel::default(), client) {
+                continue;
+            }
+            if rule.actions.contains(&Action::ForceServerSideDecorations) {
+                out = true;
+            }
+        }
+        return out;
+    }
+
+    /// How to isolate the client's clipboard, if at all.
+    pub fn clipboard_isolation(&self, client: &ClientInfo) -> Option<ClipboardIsolation> {
+        let mut out = None;
+        for rule in &self.rules {
+            if !rule.match_.matches_toplevel(&Toplevel::default(), client) {
+                continue;
+            }
+            for action in &rule.actions {
+                if let Action::IsolateClipboard(v) = action {
+                    out = Some(*v);
+                }
+            }
+        }
+        return out;
+    }
+
+    /// Returns the app id to send upstream.
+    pub fn app_id(&self, toplevel: &Toplevel, client: &ClientInfo) -> Option<String> {
+        let original = toplevel.app_id.as_deref();
+        let mut out = toplevel.app_id.clone();
+        for rule in &self.rules {
+            if !rule.match_.matches_toplevel(toplevel, client) {
+                continue;
+            }
+            for action in &rule.actions {
+                match action {
+                    Action::SetAppId(v) => out = Some(v.render(original, client, false)),
+                    Action::PrefixAppId(v) => {
+                        out = Some(format!("{}{}", v.render(original, client, false), out.unwrap_or_default()));
+                    },
+                    Action::SuffixAppId(v) => {
+                        out = Some(format!("{}{}", out.unwrap_or_default(), v.render(original, client, false)));
+                    },
+                    Action::ReplaceAppId(r) => {
+                        let with = r.with.render(original, client, true);
+                        out = out.map(|o| r.regex.0.replace_all(&o, with.as_str()).into_owned());
+                    },
+                    _ => { },
+                }
+            }
+        }
+        return limit_rewritten(original, out);
+    }
+
+    /// Returns the title to send upstream.
+    pub fn title(&self, toplevel: &Toplevel, client: &ClientInfo) -> Option<String> {
+        let original = toplevel.title.as_deref();
+        let mut out = toplevel.title.clone();
+        for rule in &self.rules {
+            if !rule.match_.matches_toplevel(toplevel, client) {
+                continue;
+            }
+            for action in &rule.actions {
+                match action {
+                    Action::SetTitle(v) => out = Some(v.render(original, client, false)),
+                    Action::PrefixTitle(v) => {
+                        out = Some(format!("{}{}", v.render(original, client, false), out.unwrap_or_default()));
+                    },
+                    Action::SuffixTitle(v) => {
+                        out = Some(format!("{}{}", out.unwrap_or_default(), v.render(original, client, false)));
+                    },
+                    Action::ReplaceTitle(r) => {
+                        let with = r.with.render(original, client, true);
+                        out = out.map(|o| r.regex.0.replace_all(&o, with.as_str()).into_owned());
+                    },
+                    _ => { },
+                }
+            }
+        }
+        return limit_rewritten(original, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Config {
+        return serde_json::from_str(json).unwrap();
+    }
+
+    fn toplevel(app_id: &str, title: &str) -> Toplevel {
+        return Toplevel {
+            app_id: Some(app_id.to_string()),
+            title: Some(title.to_string()),
+        };
+    }
+
+    #[test]
+    fn global_rules_apply_in_order() {
+        let client = ClientInfo::default();
+        let config = parse(r#"{"rules": [
+            {"actions": ["hide_global"]},
+            {"match": {"interface": "wl_seat"}, "actions": ["show_global", {"max_version": 5}]},
+            {"match": {"interface": "wl_seat"}, "actions": [{"max_version": 7}]},
+            {"match": {"interface": "wl_output"}, "actions": ["show_global", "hide_global"]}
+        ]}"#);
+        let seat = config.global("wl_seat", &client);
+        assert!(!seat.hidden);
+        assert_eq!(seat.max_version, Some(5));
+        assert!(config.global("wl_output", &client).hidden);
+        assert!(config.global("wl_shm", &client).hidden);
+    }
+
+    #[test]
+    fn clamp_version() {
+        let client = ClientInfo::default();
+        let config = parse(r#"{"rules": [{"actions": ["clamp_version"]}]}"#);
+        let known = protocol::wl_compositor::INTERFACE.version;
+        assert_eq!(config.global("wl_compositor", &client).max_version, Some(known));
+        assert_eq!(config.global("not_a_protocol_v1", &client).max_version, None);
+
+        // A lower explicit limit wins either way round
+        let config = parse(r#"{"rules": [{"actions": [{"max_version": 1}, "clamp_version"]}]}"#);
+        assert_eq!(config.global("wl_compositor", &client).max_version, Some(1));
+    }
+
+    #[test]
+    fn rewrite_actions_chain() {
+        let client = ClientInfo {
+            pid: Some(42),
+            ..Default::default()
+        };
+        let config = parse(r#"{"rules": [
+            {"actions": [{"set_app_id": "org.{original}"}, {"suffix_app_id": ".app"}]},
+            {"actions": [{"replace_app_id": {"regex": "^org\\.(\\w+)\\.(?<rest>.*)$", "with": "${rest}-$1-{pid}"}}]},
+            {"actions": [{"prefix_title": "[{pid}] "}, {"replace_title": {"regex": "\\d+", "with": "<$0>"}}]}
+        ]}"#);
+        let toplevel = toplevel("foo", "page 3");
+        assert_eq!(config.app_id(&toplevel, &client).as_deref(), Some("app-foo-42"));
+        assert_eq!(config.title(&toplevel, &client).as_deref(), Some("[<42>] page <3>"));
+    }
+
+    #[test]
+    fn replacement_values_are_literal() {
+        let client = ClientInfo::default();
+        let config = parse(r#"{"rules": [{"actions": [{"replace_title": {"regex": "x", "with": "{original}"}}]}]}"#);
+        assert_eq!(config.title(&toplevel("a", "x $1"), &client).as_deref(), Some("x $1 $1"));
+    }
+
+    #[test]
+    fn conditions_match_original_values() {
+        let client = ClientInfo::default();
+        let config = parse(r#"{"rules": [
+            {"actions": [{"set_app_id": "foo"}]},
+            {"match": {"app_id": "foo"}, "actions": [{"set_app_id": "matched"}]},
+            {"match": {"app_id_regex": "^ba"}, "actions": [{"suffix_app_id": "!"}]}
+        ]}"#);
+        assert_eq!(config.app_id(&toplevel("bar", ""), &client).as_deref(), Some("foo!"));
+        assert_eq!(config.app_id(&toplevel("foo", ""), &client).as_deref(), Some("matched"));
+    }
+
+    #[test]
+    fn rewritten_values_are_limited() {
+        let client = ClientInfo::default();
+        let long = "é".repeat(MAX_REWRITTEN_LEN);
+        let config = parse(r#"{"rules": [{"actions": [{"prefix_title": "x"}]}]}"#);
+        let title = config.title(&toplevel("", &long), &client).unwrap();
+        assert_eq!(title.len(), MAX_REWRITTEN_LEN - 1);
+        assert!(title.starts_with("xé"));
+
+        // Values sent unchanged are up to the client
+        let config = parse(r#"{"rules": []}"#);
+        assert_eq!(config.title(&toplevel("", &long), &client), Some(long));
+    }
+
+    #[test]
+    fn glob() {
+        let glob: ConfigGlob = serde_json::from_str(r#""*/app-?.scope""#).unwrap();
+        assert!(glob.0.is_match("/user.slice/app-x.scope"));
+        assert!(glob.0.is_match("/app-y.scope"));
+        assert!(!glob.0.is_match("/app-xy.scope"));
+        assert!(!glob.0.is_match("/app-x.scope/child"));
+        assert!(!glob.0.is_match("/app-x_scope"));
+    }
+
+    #[test]
+    fn client_conditions() {
+        let config = parse(r#"{"rules": [
+            {"match": {"cgroup": "*/app-flatpak-*.scope", "uid": 1000}, "actions": ["hide_global"]}
+        ]}"#);
+        let mut client = ClientInfo {
+            uid: Some(1000),
+            cgroup: Some("/user.slice/app-flatpak-org.foo-12.scope".to_string()),
+            ..Default::default()
+        };
+        assert!(config.global("wl_seat", &client).hidden);
+        client.uid = Some(1001);
+        assert!(!config.global("wl_seat", &client).hidden);
+    }
+}
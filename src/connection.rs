@@ -0,0 +1,1361 @@
+use {
+    crate::{
+        client::ClientInfo,
+        clipboard::{
+            Clipboard,
+            Delivery,
+            SelectionKind,
+            UNISOLATED_INTERFACES,
+        },
+        config::{
+            ClipboardIsolation,
+            Config,
+            Toplevel,
+        },
+        globals::{
+            Global,
+            Globals,
+        },
+        ids::IdMap,
+        objects::{
+            Object,
+            Objects,
+            SERVER_ID_START,
+        },
+        proto::{
+            self,
+            Arg,
+            ArgType,
+            Packet,
+        },
+        protocol::{
+            self,
+            Interface,
+            Message,
+        },
+        stream::{
+            ReadStatus,
+            Stream,
+            MAX_WRITE_BACKLOG,
+        },
+        Errorize,
+    },
+    rustix::fd::{
+        AsRawFd,
+        OwnedFd,
+    },
+    std::{
+        collections::{
+            HashMap,
+            VecDeque,
+        },
+        os::unix::net::UnixStream,
+    },
+};
+
+/// Fds are sent out of band, so the only way to tell which belong to a message is
+/// from its signature. Returns `None` if the message is unknown.
+fn fd_count(objects: &Objects, ids: &IdMap, packet: &Packet, requests: bool) -> Option<usize> {
+    let messages = if requests {
+        match objects.get_local(packet.id) {
+            Some(o) => o,
+            None => objects.get(ids.request_id(packet.id)?)?,
+        }.interface.requests
+    } else {
+        objects.get(packet.id)?.interface.events
+    };
+    let message = messages.get(packet.opcode as usize)?;
+    return Some(message.args.iter().filter(|a| a.type_ == ArgType::Fd).count());
+}
+
+/// A decoration filterway created to force server-side decorations on a toplevel.
+struct ForcedDecoration {
+    /// The upstream decoration object
+    proxy: u32,
+    /// The client's decoration object, which only exists downstream
+    client: Option<u32>,
+    /// The last mode the compositor configured
+    mode: Option<u32>,
+}
+
+#[derive(Default)]
+struct ToplevelState {
+    original: Toplevel,
+    sent_app_id: Option<String>,
+    sent_title: Option<String>,
+    decoration: Option<ForcedDecoration>,
+}
+
+/// A global the compositor advertised, whether or not the client can see it.
+struct UpstreamGlobal {
+    /// The registry it was advertised on (upstream id)
+    registry: u32,
+    name: u32,
+    version: u32,
+}
+
+/// Recompute a toplevel's app id and title after the client set one of them.
+/// `packet` is the client's `set_title` or `set_app_id` request and is rewritten in
+/// place. Rules can match on both values, so if the other value changes as a result
+/// a request updating it is added to `extra`.
+fn rewrite_toplevel(
+    config: &Config,
+    client: &ClientInfo,
+    state: &mut ToplevelState,
+    packet: &mut Packet,
+    extra: &mut Vec<Packet>,
+    debug: bool,
+) {
+    for (opcode, value, sent) in [
+        (protocol::xdg_toplevel::REQ_SET_TITLE, config.title(&state.original, client), &mut state.sent_title),
+        (protocol::xdg_toplevel::REQ_SET_APP_ID, config.app_id(&state.original, client), &mut state.sent_app_id),
+    ] {
+        if opcode != packet.opcode && (sent.is_none() || value == *sent) {
+            continue;
+        }
+        let mut body = vec![];
+        proto::encode_args(&[Arg::String(value.clone())], &mut body, &mut vec![]).unwrap();
+        if opcode == packet.opcode {
+            packet.body = body;
+            if debug && value != *sent {
+                eprintln!("Rewrote toplevel request; new message: {:?}", packet);
+            }
+        } else {
+            extra.push(Packet {
+                id: packet.id,
+                opcode: opcode,
+                body: body,
+            });
+        }
+        *sent = value;
+    }
+}
+
+/// Replace the client's ids in a request's arguments with upstream ids, mapping
+/// new objects. Returns whether any changed.
+fn translate_request_args(ids: &mut IdMap, args: &mut [Arg]) -> Result<bool, String> {
+    let mut changed = false;
+    for arg in args {
+        let (id, upstream) = match arg {
+            Arg::NewId(id) | Arg::UntypedNewId { id, .. } => {
+                let upstream = ids.client_new(*id).ok_or_else(|| format!("invalid new id {}", id))?;
+                (id, upstream)
+            },
+            Arg::Object(id) if *id != 0 => {
+                let upstream = ids.request_id(*id).ok_or_else(|| format!("invalid object {}", id))?;
+                (id, upstream)
+            },
+            _ => continue,
+        };
+        if *id != upstream {
+            *id = upstream;
+            changed = true;
+        }
+    }
+    return Ok(changed);
+}
+
+/// Replace upstream ids in the arguments of an event to `parent` with the
+/// client's ids, mapping new objects. References to objects the client can't see
+/// become null. Returns whether any changed.
+fn translate_event_args(ids: &mut IdMap, parent: u32, args: &mut [Arg]) -> bool {
+    let mut changed = false;
+    for arg in args {
+        let (id, downstream) = match arg {
+            Arg::NewId(id) | Arg::UntypedNewId { id, .. } => {
+                let downstream = ids.server_new(parent, *id);
+                (id, downstream)
+            },
+            Arg::Object(id) if *id != 0 => {
+                let downstream = ids.event_id(*id);
+                (id, downstream)
+            },
+            _ => continue,
+        };
+        let downstream = downstream.unwrap_or(0);
+        if *id != downstream {
+            *id = downstream;
+            changed = true;
+        }
+    }
+    return changed;
+}
+
+fn build_packet(id: u32, opcode: u16, args: &[Arg]) -> Packet {
+    let mut packet = Packet {
+        id: id,
+        opcode: opcode,
+        body: vec![],
+    };
+    proto::encode_args(args, &mut packet.body, &mut vec![]).unwrap();
+    return packet;
+}
+
+/// Build a `wl_display.error` event. Messages are prefixed so users can tell the
+/// error came from filterway and not the compositor.
+pub fn display_error(object: u32, code: u32, message: &str) -> Packet {
+    let mut packet = proto::Packet {
+        id: 1,
+        opcode: protocol::wl_display::EVT_ERROR,
+        body: vec![],
+    };
+    proto::encode_args(
+        &[Arg::Object(object), Arg::Uint(code), Arg::String(Some(format!("filterway: {}", message)))],
+        &mut packet.body,
+        &mut vec![],
+    ).unwrap();
+    return packet;
+}
+
+/// A compositor offer hidden from a client with an isolated clipboard, until it's
+/// known what it's for.
+struct HostOffer {
+    /// The device the offer was made to (upstream id)
+    device: u32,
+    kind: SelectionKind,
+    mime_types: Vec<String>,
+    /// Events for the offer, to replay if it's revealed to the client
+    events: Vec<Packet>,
+}
+
+/// What an offer filterway made to the client is for.
+#[derive(Clone, Copy, PartialEq)]
+enum LocalOffer {
+    /// An isolated selection, by serial
+    Isolated(u64),
+    /// The compositor's selection (upstream offer id), for `paste_from_host`
+    Host(u32),
+}
+
+/// What an object filterway owns upstream is for, which decides where its events
+/// go.
+#[derive(Clone, Copy, Debug)]
+pub enum ProxyRole {
+    DecorationManager,
+    /// A forced decoration, for the toplevel (upstream id)
+    Decoration(u32),
+    /// A compositor offer hidden from the client, in `host_offers`
+    HostOffer,
+}
+
+/// A downstream client and its upstream connection, plus everything filterway
+/// knows about the objects on it.
+pub struct Connection {
+    pub client: ClientInfo,
+    pub downstream: Stream,
+    pub upstream: Stream,
+    debug: bool,
+    objects: Objects,
+    ids: IdMap,
+    globals: Globals,
+    toplevels: HashMap<u32, ToplevelState>,
+    /// Objects filterway owns upstream (see `create_proxy_object`)
+    proxies: HashMap<u32, ProxyRole>,
+    decoration_global: Option<UpstreamGlobal>,
+    /// Filterway's own decoration manager, bound on first use
+    decoration_manager: Option<u32>,
+    /// The client's decoration objects for toplevels with forced decorations, to the
+    /// toplevel (upstream id)
+    client_decorations: HashMap<u32, u32>,
+    /// Mime types offered by the client's data sources (upstream ids)
+    selection_sources: HashMap<u32, Vec<String>>,
+    /// The client's data devices (upstream ids)
+    selection_devices: HashMap<u32, SelectionKind>,
+    /// Offers filterway made to the client for isolated clients' selections or the
+    /// compositor's
+    local_offers: HashMap<u32, LocalOffer>,
+    /// Compositor offers hidden from the client, by upstream id. Selection offers
+    /// stay here while they're the compositor's selection.
+    host_offers: HashMap<u32, HostOffer>,
+    /// The compositor's selection offer for each device
+    host_selections: HashMap<u32, u32>,
+    /// A `wl_display.error` was sent to the client, from filterway or forwarded from
+    /// the compositor. Only the first one matters to the client.
+    error_sent: bool,
+    injected_requests: Vec<(Packet, Vec<OwnedFd>)>,
+    injected_events: Vec<(Packet, Vec<OwnedFd>)>,
+}
+
+impl Connection {
+    pub fn new(client: ClientInfo, downstream: UnixStream, upstream: UnixStream, debug: bool) -> Result<Self, String> {
+        return Ok(Self {
+            client: client,
+            downstream: Stream::new(downstream)?,
+            upstream: Stream::new(upstream)?,
+            debug: debug,
+            objects: Objects::default(),
+            ids: IdMap::default(),
+            globals: Globals::default(),
+            toplevels: HashMap::new(),
+            decoration_global: None,
+            proxies: HashMap::new(),
+            decoration_manager: None,
+            client_decorations: HashMap::new(),
+            selection_sources: HashMap::new(),
+            selection_devices: HashMap::new(),
+            local_offers: HashMap::new(),
+            host_offers: HashMap::new(),
+            host_selections: HashMap::new(),
+            error_sent: false,
+            injected_requests: vec![],
+            injected_events: vec![],
+        });
+    }
+
+    /// Process everything available in both directions and send what can be sent.
+    /// Returns false once either side has closed. On error the client is sent a
+    /// `wl_display.error` saying why (if it didn't get a more specific one already)
+    /// and should be disconnected.
+    pub fn pump(&mut self, config: &Config, clipboard: &mut Clipboard) -> Result<bool, String> {
+        let res = self.pump_inner(config, clipboard);
+        if let Err(e) = &res {
+            self.send_display_error(1, protocol::wl_display::ERROR_IMPLEMENTATION, e.clone());
+            _ = self.queue_injected();
+            _ = self.downstream.flush();
+        }
+        return res;
+    }
+
+    fn pump_inner(&mut self, config: &Config, clipboard: &mut Clipboard) -> Result<bool, String> {
+        // When a side isn't read because the other side is backed up, the send that
+        // left the backlog returned `EAGAIN`, so the other socket gets an edge-triggered
+        // writable event once it drains and reading resumes then.
+        let downstream_open = loop {
+            if self.upstream.write_backlog() > MAX_WRITE_BACKLOG {
+                self.flush()?;
+                if self.upstream.write_backlog() > MAX_WRITE_BACKLOG {
+                    break true;
+                }
+            }
+            let status = self.downstream.fill().context("Error reading from downstream")?;
+            loop {
+                let (packet, fds) = match self.downstream.next_packet(|p| fd_count(&self.objects, &self.ids, p, true)) {
+                    Ok(Some(next)) => next,
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.send_display_error(1, protocol::wl_display::ERROR_INVALID_METHOD, e.clone());
+                        return Err(format!("Error reading from downstream: {}", e));
+                    },
+                };
+                self
+                    .handle_request(config, clipboard, packet, fds)
+                    .context("Error handling client->server message")?;
+                self.queue_injected()?;
+            }
+            match status {
+                ReadStatus::Full => { },
+                ReadStatus::Drained => break true,
+                ReadStatus::Closed => break false,
+            }
+        };
+        let upstream_open = loop {
+            if self.downstream.write_backlog() > MAX_WRITE_BACKLOG {
+                self.flush()?;
+                if self.downstream.write_backlog() > MAX_WRITE_BACKLOG {
+                    break true;
+                }
+            }
+            let status = self.upstream.fill().context("Error reading from upstream")?;
+            while let Some((packet, fds)) =
+                self
+                    .upstream
+                    .next_packet(|p| fd_count(&self.objects, &self.ids, p, false))
+                    .context("Error reading from upstream")? {
+                self
+                    .handle_event(config, clipboard, packet, fds)
+                    .context("Error handling server->client message")?;
+                self.queue_injected()?;
+            }
+            match status {
+                ReadStatus::Full => { },
+                ReadStatus::Drained => break true,
+                ReadStatus::Closed => break false,
+            }
+        };
+        if downstream_open && !upstream_open {
+            self.send_display_error(
+                1,
+                protocol::wl_display::ERROR_IMPLEMENTATION,
+                "compositor closed the connection".to_string(),
+            );
+        }
+        self.flush()?;
+        return Ok(downstream_open && upstream_open);
+    }
+
+    /// Send injected messages and anything else buffered.
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.queue_injected()?;
+        self.upstream.flush().context("Error writing to upstream")?;
+        self.downstream.flush().context("Error writing to downstream")?;
+        return Ok(());
+    }
+
+    /// Send a request to the compositor after the message currently being handled.
+    /// Object ids in it are upstream ids, and new objects must use ids from
+    /// `create_proxy_object`.
+    pub fn inject_request(&mut self, packet: Packet, fds: Vec<OwnedFd>) {
+        self.injected_requests.push((packet, fds));
+    }
+
+    /// Send an event to the client after the message currently being handled. Object
+    /// ids in it are the client's ids.
+    pub fn inject_event(&mut self, packet: Packet, fds: Vec<OwnedFd>) {
+        self.injected_events.push((packet, fds));
+    }
+
+    /// Allocate an id for an object filterway creates on the upstream connection, to
+    /// use as a new id in an injected request. The client never sees the object;
+    /// events for it and objects created from it go to `handle_proxy_event`, which
+    /// passes them on according to `role`. Returns `None` if the client has used
+    /// messages filterway couldn't parse, which may have taken ids filterway doesn't
+    /// know about.
+    pub fn create_proxy_object(&mut self, interface: &'static Interface, version: u32, role: ProxyRole) -> Option<u32> {
+        if !self.ids.can_create() {
+            return None;
+        }
+        let id = self.ids.proxy_new();
+        self.proxies.insert(id, role);
+        self.objects.insert(id, Object {
+            interface: interface,
+            version: version,
+        });
+        return Some(id);
+    }
+
+    fn queue_injected(&mut self) -> Result<(), String> {
+        for (packet, fds) in self.injected_requests.drain(..) {
+            if self.debug {
+                eprintln!("Sending synthetic request upstream: {:?}", packet);
+            }
+            self.upstream.queue(&packet, fds)?;
+        }
+        for (packet, fds) in self.injected_events.drain(..) {
+            if self.debug {
+                eprintln!("Sending synthetic event downstream: {:?}", packet);
+            }
+            self.downstream.queue(&packet, fds)?;
+        }
+        return Ok(());
+    }
+
+    /// Queue a `wl_display.error` event to the client, unless it's already been sent
+    /// one. Protocol errors are fatal so the connection should be closed afterwards.
+    fn send_display_error(&mut self, object: u32, code: u32, message: String) {
+        if self.error_sent {
+            return;
+        }
+        self.error_sent = true;
+        self.inject_event(display_error(object, code, &message), vec![]);
+    }
+
+    /// Handle an event for an object filterway created, instead of forwarding it.
+    fn handle_proxy_event(&mut self, o: Object, packet: &Packet, args: &[Arg]) {
+        if self.debug {
+            eprintln!("Received event for proxy object {:?}: {:?} {:?}", o, packet, args);
+        }
+        match self.proxies.get(&packet.id) {
+            Some(ProxyRole::DecorationManager) | None => { },
+            Some(ProxyRole::Decoration(toplevel)) => {
+                let toplevel = *toplevel;
+                self.handle_decoration_event(toplevel, packet, args);
+            },
+            Some(ProxyRole::HostOffer) => {
+                let Some(host) = self.host_offers.get_mut(&packet.id) else {
+                    return;
+                };
+                if let (true, [Arg::String(Some(mime_type))]) = (packet.opcode == host.kind.offer_offer(), args) {
+                    host.mime_types.push(mime_type.clone());
+                }
+                host.events.push(Packet {
+                    id: packet.id,
+                    opcode: packet.opcode,
+                    body: packet.body.clone(),
+                });
+            },
+        }
+    }
+
+    /// Handle an event for a forced decoration of a toplevel (upstream id).
+    fn handle_decoration_event(&mut self, toplevel: u32, packet: &Packet, args: &[Arg]) {
+        let Some(decoration) = self.toplevels.get_mut(&toplevel).and_then(|t| t.decoration.as_mut()) else {
+            return;
+        };
+        if let (protocol::zxdg_toplevel_decoration_v1::EVT_CONFIGURE, [Arg::Uint(mode)]) = (packet.opcode, args) {
+            decoration.mode = Some(*mode);
+            if let Some(client) = decoration.client {
+                self.inject_event(build_packet(client, packet.opcode, args), vec![]);
+            }
+        }
+    }
+
+    /// Create a server-side decoration for a new toplevel (upstream id).
+    fn force_decoration(&mut self, toplevel: u32) {
+        let Some(global) = &self.decoration_global else {
+            if self.debug {
+                eprintln!("Compositor has no decoration manager, can't force decorations");
+            }
+            return;
+        };
+        if !self.ids.can_create() {
+            eprintln!(
+                "Warning, client {} used protocols filterway doesn't know, can't force decorations",
+                self.client.conn_id
+            );
+            return;
+        }
+        let manager = match self.decoration_manager {
+            Some(manager) => manager,
+            None => {
+                let version = global.version.min(protocol::zxdg_decoration_manager_v1::INTERFACE.version);
+                let registry = global.registry;
+                let name = global.name;
+                let manager =
+                    self
+                        .create_proxy_object(
+                            &protocol::zxdg_decoration_manager_v1::INTERFACE,
+                            version,
+                            ProxyRole::DecorationManager,
+                        )
+                        .unwrap();
+                self.inject_request(
+                    build_packet(
+                        registry,
+                        protocol::wl_registry::REQ_BIND,
+                        &[
+                            Arg::Uint(name),
+                            Arg::UntypedNewId {
+                                interface: Some(protocol::zxdg_decoration_manager_v1::INTERFACE.name.to_string()),
+                                version: version,
+                                id: manager,
+                            },
+                        ],
+                    ),
+                    vec![],
+                );
+                self.decoration_manager = Some(manager);
+                manager
+            },
+        };
+        let version = self.objects.get(manager).map(|o| o.version).unwrap_or(1);
+        let decoration =
+            self
+                .create_proxy_object(
+                    &protocol::zxdg_toplevel_decoration_v1::INTERFACE,
+                    version,
+                    ProxyRole::Decoration(toplevel),
+                )
+                .unwrap();
+        self.inject_request(
+            build_packet(
+                manager,
+                protocol::zxdg_decoration_manager_v1::REQ_GET_TOPLEVEL_DECORATION,
+                &[Arg::NewId(decoration), Arg::Object(toplevel)],
+            ),
+            vec![],
+        );
+        self.inject_request(
+            build_packet(
+                decoration,
+                protocol::zxdg_toplevel_decoration_v1::REQ_SET_MODE,
+                &[Arg::Uint(protocol::zxdg_toplevel_decoration_v1::MODE_SERVER_SIDE)],
+            ),
+            vec![],
+        );
+        self.toplevels.entry(toplevel).or_default().decoration = Some(ForcedDecoration {
+            proxy: decoration,
+            client: None,
+            mode: None,
+        });
+    }
+
+    /// Offer the selection to one of the client's devices (upstream id), if its
+    /// clipboard is isolated: an isolated client's selection, otherwise the
+    /// compositor's for `paste_from_host`, otherwise none.
+    fn offer_selection(&mut self, config: &Config, clipboard: &Clipboard, device: u32, kind: SelectionKind) {
+        let Some(device_id) = self.ids.event_id(device) else {
+            return;
+        };
+        let host = self.host_selections.get(&device).and_then(|offer| Some((*offer, self.host_offers.get(offer)?)));
+        let (source, mime_types) = match (clipboard.get(kind), config.clipboard_isolation(&self.client), host) {
+            (Some(selection), Some(_), _) => {
+                (Some(LocalOffer::Isolated(selection.serial)), selection.mime_types.clone())
+            },
+            (None, Some(ClipboardIsolation::PasteFromHost), Some((offer, host))) => {
+                (Some(LocalOffer::Host(offer)), host.mime_types.clone())
+            },
+            _ => (None, vec![]),
+        };
+        let Some(source) = source else {
+            self.inject_event(build_packet(device_id, kind.device_selection(), &[Arg::Object(0)]), vec![]);
+            return;
+        };
+        let offer = self.ids.server_local();
+        self.objects.insert_local(offer, Object {
+            interface: kind.offer_interface(),
+            version: self.objects.get(device).map(|o| o.version).unwrap_or(1),
+        });
+        self.local_offers.insert(offer, source);
+        self.inject_event(build_packet(device_id, kind.device_data_offer(), &[Arg::NewId(offer)]), vec![]);
+        for mime_type in &mime_types {
+            self.inject_event(
+                build_packet(offer, kind.offer_offer(), &[Arg::String(Some(mime_type.clone()))]),
+                vec![],
+            );
+        }
+        self.inject_event(build_packet(device_id, kind.device_selection(), &[Arg::Object(offer)]), vec![]);
+    }
+
+    /// Tell the client about a changed isolated selection, if its clipboard is
+    /// isolated.
+    pub fn offer_selections(&mut self, config: &Config, clipboard: &Clipboard, kind: SelectionKind) {
+        if config.clipboard_isolation(&self.client).is_none() {
+            return;
+        }
+        let devices =
+            self.selection_devices.iter().filter(|(_, k)| **k == kind).map(|(id, _)| *id).collect::<Vec<_>>();
+        for device in devices {
+            self.offer_selection(config, clipboard, device, kind);
+        }
+    }
+
+    /// Whether a message filterway couldn't parse can be passed through (see
+    /// `IdMap::pass_opaque`). Never for clients with an isolated clipboard, which
+    /// always need objects of filterway's own.
+    fn pass_opaque(&mut self, config: &Config) -> bool {
+        return config.clipboard_isolation(&self.client).is_none() && self.ids.pass_opaque();
+    }
+
+    /// Destroy a compositor offer hidden from the client.
+    fn destroy_host_offer(&mut self, offer: u32) {
+        let Some(host) = self.host_offers.remove(&offer) else {
+            return;
+        };
+        self.inject_request(build_packet(offer, host.kind.offer_destroy(), &[]), vec![]);
+        self.proxies.remove(&offer);
+        self.objects.remove(offer);
+        self.ids.remove(offer);
+    }
+
+    /// Destroy a device's hidden compositor offers, except its selection if `keep`
+    /// is set.
+    fn destroy_host_offers(&mut self, device: u32, keep: Option<u32>) {
+        let offers =
+            self
+                .host_offers
+                .iter()
+                .filter(|(id, h)| h.device == device && Some(**id) != keep)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+        for offer in offers {
+            self.destroy_host_offer(offer);
+        }
+    }
+
+    /// The compositor set the selection for a device of a client with an isolated
+    /// clipboard. The offer (hidden from the client) is kept for `paste_from_host`,
+    /// otherwise destroyed.
+    fn set_host_selection(
+        &mut self,
+        config: &Config,
+        clipboard: &Clipboard,
+        device: u32,
+        kind: SelectionKind,
+        offer: u32,
+    ) {
+        let paste_from_host = config.clipboard_isolation(&self.client) == Some(ClipboardIsolation::PasteFromHost);
+        self.host_selections.remove(&device);
+        if paste_from_host && self.host_offers.contains_key(&offer) {
+            self.host_selections.insert(device, offer);
+        }
+        self.destroy_host_offers(device, self.host_selections.get(&device).copied());
+        if paste_from_host && clipboard.get(kind).is_none() {
+            self.offer_selection(config, clipboard, device, kind);
+        }
+    }
+
+    /// Hide the compositor's offers from a client with an isolated clipboard until
+    /// they're used for drag and drop. Returns whether the event is held back.
+    fn hold_host_event(
+        &mut self,
+        config: &Config,
+        clipboard: &Clipboard,
+        device: u32,
+        o: Object,
+        opcode: u16,
+        args: &[Arg],
+    ) -> Result<bool, String> {
+        match (o.interface.name, opcode, args) {
+            ("wl_data_device", protocol::wl_data_device::EVT_DATA_OFFER, [Arg::NewId(offer)]) |
+            (
+                "zwp_primary_selection_device_v1",
+                protocol::zwp_primary_selection_device_v1::EVT_DATA_OFFER,
+                [Arg::NewId(offer)],
+            ) => {
+                // An offer that was never used
+                self.destroy_host_offers(device, self.host_selections.get(&device).copied());
+                self.ids.server_proxy(*offer);
+                self.proxies.insert(*offer, ProxyRole::HostOffer);
+                self.host_offers.insert(*offer, HostOffer {
+                    device: device,
+                    kind: SelectionKind::of(o.interface.name).unwrap(),
+                    mime_types: vec![],
+                    events: vec![],
+                });
+                return Ok(true);
+            },
+            ("wl_data_device", protocol::wl_data_device::EVT_ENTER, [.., Arg::Object(offer)]) => {
+                self.reveal_host_offer(*offer)?;
+                return Ok(false);
+            },
+            ("wl_data_device", protocol::wl_data_device::EVT_SELECTION, [Arg::Object(offer)]) |
+            (
+                "zwp_primary_selection_device_v1",
+                protocol::zwp_primary_selection_device_v1::EVT_SELECTION,
+                [Arg::Object(offer)],
+            ) => {
+                if self.debug {
+                    eprintln!("Hiding compositor selection from isolated client");
+                }
+                let kind = SelectionKind::of(o.interface.name).unwrap();
+                self.set_host_selection(config, clipboard, device, kind, *offer);
+                return Ok(true);
+            },
+            _ => return Ok(false),
+        }
+    }
+
+    /// The compositor offered something for drag and drop, which isn't isolated;
+    /// show the client the offer before the event that uses it.
+    fn reveal_host_offer(&mut self, offer: u32) -> Result<(), String> {
+        let Some(host) = self.host_offers.remove(&offer) else {
+            return Ok(());
+        };
+        let Some(device) = self.ids.event_id(host.device) else {
+            return Ok(());
+        };
+        self.proxies.remove(&offer);
+        let downstream = self.ids.reveal(offer);
+        self.downstream.queue(&build_packet(device, host.kind.device_data_offer(), &[Arg::NewId(downstream)]), vec![])?;
+        for mut event in host.events {
+            event.id = downstream;
+            self.downstream.queue(&event, vec![])?;
+        }
+        return Ok(());
+    }
+
+    /// Handle a message from another connection.
+    pub fn deliver(&mut self, delivery: Delivery) {
+        if self.debug {
+            eprintln!("Received message from another connection: {:?}", delivery);
+        }
+        match delivery {
+            Delivery::Send { kind, source, mime_type, fd } => {
+                let Some(source) = self.ids.event_id(source) else {
+                    return;
+                };
+                self.inject_event(
+                    build_packet(source, kind.source_send(), &[Arg::String(Some(mime_type)), Arg::Fd(fd.as_raw_fd())]),
+                    vec![fd],
+                );
+            },
+            Delivery::Cancelled { kind, source } => {
+                let Some(source) = self.ids.event_id(source) else {
+                    return;
+                };
+                self.inject_event(build_packet(source, kind.source_cancelled(), &[]), vec![]);
+            },
+        }
+    }
+
+    /// Decode a request's arguments, sending the client an error if they're invalid.
+    fn decode_request(
+        &mut self,
+        o: Object,
+        message: &Message,
+        downstream_id: u32,
+        packet: &Packet,
+        fds: &[OwnedFd],
+    ) -> Result<Vec<Arg>, String> {
+        match proto::decode_args(
+            &packet.body,
+            &message.signature(),
+            &mut fds.iter().map(|fd| fd.as_raw_fd()).collect::<VecDeque<_>>(),
+        ) {
+            Ok(a) => return Ok(a),
+            Err(e) => {
+                let error =
+                    format!("invalid arguments for {}@{}.{}: {}", o.interface.name, downstream_id, message.name, e);
+                self.send_display_error(downstream_id, protocol::wl_display::ERROR_INVALID_METHOD, error.clone());
+                return Err(error);
+            },
+        }
+    }
+
+    /// Handle a request for an object that only exists on the client's side: the
+    /// client's decorations for toplevels with forced decorations (mode requests are
+    /// dropped), and offers for isolated selections.
+    fn handle_local_request(
+        &mut self,
+        clipboard: &mut Clipboard,
+        o: Object,
+        packet: Packet,
+        fds: Vec<OwnedFd>,
+    ) -> Result<(), String> {
+        if self.debug {
+            eprintln!("Request is for local object {:?}, handling in filterway", o);
+        }
+        let Some(message) = o.interface.requests.get(packet.opcode as usize) else {
+            let error = format!("invalid opcode {} for {}@{}", packet.opcode, o.interface.name, packet.id);
+            self.send_display_error(packet.id, protocol::wl_display::ERROR_INVALID_METHOD, error.clone());
+            return Err(error);
+        };
+        let message_args = self.decode_request(o, message, packet.id, &packet, &fds)?;
+        match (o.interface.name, packet.opcode, message_args.as_slice()) {
+            ("zxdg_toplevel_decoration_v1", protocol::zxdg_toplevel_decoration_v1::REQ_DESTROY, _) => {
+                if let Some(toplevel) = self.client_decorations.remove(&packet.id) {
+                    if let Some(decoration) =
+                        self.toplevels.get_mut(&toplevel).and_then(|t| t.decoration.as_mut()) {
+                        decoration.client = None;
+                    }
+                }
+                self.objects.remove_local(packet.id);
+                self.ids.remove_local(packet.id);
+                self.inject_event(
+                    build_packet(1, protocol::wl_display::EVT_DELETE_ID, &[Arg::Uint(packet.id)]),
+                    vec![],
+                );
+            },
+            (
+                "wl_data_offer",
+                protocol::wl_data_offer::REQ_RECEIVE,
+                [Arg::String(Some(mime_type)), Arg::Fd(_)],
+            ) | (
+                "zwp_primary_selection_offer_v1",
+                protocol::zwp_primary_selection_offer_v1::REQ_RECEIVE,
+                [Arg::String(Some(mime_type)), Arg::Fd(_)],
+            ) => {
+                let kind = SelectionKind::of(o.interface.name).unwrap();
+                let fd = fds.into_iter().next().unwrap();
+                match (self.local_offers.get(&packet.id).copied(), clipboard.get(kind)) {
+                    (Some(LocalOffer::Isolated(serial)), Some(selection)) if selection.serial == serial => {
+                        clipboard.outbox.push((selection.conn_id, Delivery::Send {
+                            kind: kind,
+                            source: selection.source,
+                            mime_type: mime_type.clone(),
+                            fd: fd,
+                        }));
+                    },
+                    (Some(LocalOffer::Host(offer)), _) if self.host_offers.contains_key(&offer) => {
+                        self.inject_request(
+                            build_packet(
+                                offer,
+                                kind.offer_receive(),
+                                &[Arg::String(Some(mime_type.clone())), Arg::Fd(fd.as_raw_fd())],
+                            ),
+                            vec![fd],
+                        );
+                    },
+                    _ => {
+                        // Closing the fd tells the client there's no data
+                        if self.debug {
+                            eprintln!("Client pasted from a selection that's since changed, ignoring");
+                        }
+                    },
+                }
+            },
+            ("wl_data_offer", protocol::wl_data_offer::REQ_DESTROY, _) |
+            ("zwp_primary_selection_offer_v1", protocol::zwp_primary_selection_offer_v1::REQ_DESTROY, _) => {
+                self.local_offers.remove(&packet.id);
+                self.objects.remove_local(packet.id);
+                self.ids.remove_local(packet.id);
+            },
+            _ => { },
+        }
+        return Ok(());
+    }
+
+    fn handle_request(
+        &mut self,
+        config: &Config,
+        clipboard: &mut Clipboard,
+        mut packet: Packet,
+        fds: Vec<OwnedFd>,
+    ) -> Result<(), String> {
+        let mut forward = true;
+        let mut send_before = vec![];
+        let mut send_extra = vec![];
+        if self.debug {
+            eprintln!("Received packet from downstream with {} ancillary FDs: {:?}", fds.len(), packet);
+        }
+        if let Some(o) = self.objects.get_local(packet.id) {
+            return self.handle_local_request(clipboard, o, packet, fds);
+        }
+
+        // Everything past here uses upstream ids
+        let downstream_id = packet.id;
+        let Some(id) = self.ids.request_id(downstream_id) else {
+            let error = format!("invalid object {}", downstream_id);
+            self.send_display_error(downstream_id, protocol::wl_display::ERROR_INVALID_OBJECT, error.clone());
+            return Err(error);
+        };
+        packet.id = id;
+
+        // Track and prepare manipulations
+        let o = self.objects.get(packet.id);
+        if self.debug {
+            eprintln!("Request is for tracked object {:?} (upstream id {})", o, packet.id);
+        }
+        let parsed = o.is_some_and(|o| o.interface.requests.get(packet.opcode as usize).is_some());
+        if !parsed && !self.pass_opaque(config) {
+            let error =
+                format!(
+                    "can't forward unknown request {} to {}@{}, filterway creates objects of its own",
+                    packet.opcode,
+                    o.map(|o| o.interface.name).unwrap_or("untracked object"),
+                    downstream_id
+                );
+            self.send_display_error(downstream_id, protocol::wl_display::ERROR_IMPLEMENTATION, error.clone());
+            return Err(error);
+        }
+        if let Some(o) = o {
+            let message_args = match o.interface.requests.get(packet.opcode as usize) {
+                Some(message) => {
+                    let mut message_args = self.decode_request(o, message, downstream_id, &packet, &fds)?;
+                    if let (
+                        "zxdg_decoration_manager_v1",
+                        protocol::zxdg_decoration_manager_v1::REQ_GET_TOPLEVEL_DECORATION,
+                        [Arg::NewId(client_decoration), Arg::Object(toplevel)],
+                    ) = (o.interface.name, packet.opcode, message_args.as_slice()) {
+                        let toplevel = self.ids.request_id(*toplevel).unwrap_or(0);
+                        if let Some(decoration) =
+                            self.toplevels.get_mut(&toplevel).and_then(|t| t.decoration.as_mut()) {
+                            // Keep the client's decoration away from the compositor, which would
+                            // reject a second one anyway
+                            decoration.client = Some(*client_decoration);
+                            let mode = decoration.mode;
+                            if !self.ids.client_local(*client_decoration) {
+                                let error = format!("invalid new id {}", client_decoration);
+                                self.send_display_error(
+                                    downstream_id,
+                                    protocol::wl_display::ERROR_INVALID_OBJECT,
+                                    error.clone(),
+                                );
+                                return Err(error);
+                            }
+                            self.objects.insert_local(*client_decoration, Object {
+                                interface: &protocol::zxdg_toplevel_decoration_v1::INTERFACE,
+                                version: o.version,
+                            });
+                            self.client_decorations.insert(*client_decoration, toplevel);
+                            if let Some(mode) = mode {
+                                self.inject_event(
+                                    build_packet(
+                                        *client_decoration,
+                                        protocol::zxdg_toplevel_decoration_v1::EVT_CONFIGURE,
+                                        &[Arg::Uint(mode)],
+                                    ),
+                                    vec![],
+                                );
+                            }
+                            return Ok(());
+                        }
+                    }
+                    match translate_request_args(&mut self.ids, &mut message_args) {
+                        Ok(false) => { },
+                        Ok(true) => {
+                            packet.body.clear();
+                            proto::encode_args(&message_args, &mut packet.body, &mut vec![]).unwrap();
+                        },
+                        Err(error) => {
+                            self.send_display_error(
+                                downstream_id,
+                                protocol::wl_display::ERROR_INVALID_OBJECT,
+                                error.clone(),
+                            );
+                            return Err(error);
+                        },
+                    }
+                    for i in self.objects.track(packet.id, o, message, &message_args) {
+                        if self.debug {
+                            eprintln!("Client created object with unknown interface [{}], not tracking", i);
+                        }
+                    }
+                    if message.destructor && packet.id >= SERVER_ID_START {
+                        self.ids.remove(packet.id);
+                    }
+                    Some(message_args)
+                },
+                None => {
+                    if self.debug {
+                        eprintln!("Unknown {} request opcode {}, forwarding as is", o.interface.name, packet.opcode);
+                    }
+                    None
+                },
+            };
+
+            // Opcodes never change meaning between versions (new messages are only
+            // appended) so filters apply to every version, including ones newer than the
+            // bundled protocols.
+            match (o.interface.name, packet.opcode, message_args.as_deref()) {
+                (
+                    "wl_registry",
+                    protocol::wl_registry::REQ_BIND,
+                    Some([Arg::Uint(name), Arg::UntypedNewId { interface: Some(interface), version, id }]),
+                ) => {
+                    let error = match self.globals.get(*name) {
+                        None if config.global(interface, &self.client).hidden => Some(
+                            format!("bind to hidden global {} denied", interface),
+                        ),
+                        None => Some(format!("invalid global {} ({})", interface, name)),
+                        Some(global) if global.interface != *interface => Some(
+                            format!(
+                                "invalid interface for global {}: have {}, wanted {}",
+                                name,
+                                global.interface,
+                                interface
+                            ),
+                        ),
+                        Some(global) if *version > global.version => Some(
+                            format!(
+                                "invalid version for global {} ({}): have {}, wanted {}",
+                                interface,
+                                name,
+                                global.version,
+                                version
+                            ),
+                        ),
+                        Some(global) => {
+                            if global.removed && self.debug {
+                                eprintln!("Forwarding bind to removed global {} ({})", interface, name);
+                            }
+                            packet.body.clear();
+                            proto::encode_args(
+                                &[
+                                    Arg::Uint(global.upstream_name),
+                                    Arg::UntypedNewId {
+                                        interface: Some(interface.clone()),
+                                        version: *version,
+                                        id: *id,
+                                    },
+                                ],
+                                &mut packet.body,
+                                &mut vec![],
+                            ).unwrap();
+                            None
+                        },
+                    };
+                    if let Some(error) = error {
+                        self.send_display_error(
+                            downstream_id,
+                            protocol::wl_display::ERROR_INVALID_OBJECT,
+                            error.clone(),
+                        );
+                        return Err(format!("Rejected client bind: {}", error));
+                    }
+                },
+                ("xdg_toplevel", protocol::xdg_toplevel::REQ_SET_TITLE, Some([Arg::String(title)])) => {
+                    let state = self.toplevels.entry(packet.id).or_default();
+                    state.original.title = title.clone();
+                    rewrite_toplevel(config, &self.client, state, &mut packet, &mut send_extra, self.debug);
+                },
+                ("xdg_toplevel", protocol::xdg_toplevel::REQ_SET_APP_ID, Some([Arg::String(app_id)])) => {
+                    let state = self.toplevels.entry(packet.id).or_default();
+                    state.original.app_id = app_id.clone();
+                    rewrite_toplevel(config, &self.client, state, &mut packet, &mut send_extra, self.debug);
+                },
+                ("xdg_surface", protocol::xdg_surface::REQ_GET_TOPLEVEL, Some([Arg::NewId(toplevel)])) => {
+                    if config.force_server_side_decorations(&self.client) {
+                        self.force_decoration(*toplevel);
+                    }
+                },
+                (
+                    "wl_data_device_manager",
+                    protocol::wl_data_device_manager::REQ_CREATE_DATA_SOURCE,
+                    Some([Arg::NewId(source)]),
+                ) | (
+                    "zwp_primary_selection_device_manager_v1",
+                    protocol::zwp_primary_selection_device_manager_v1::REQ_CREATE_SOURCE,
+                    Some([Arg::NewId(source)]),
+                ) => {
+                    self.selection_sources.insert(*source, vec![]);
+                },
+                ("wl_data_source", protocol::wl_data_source::REQ_OFFER, Some([Arg::String(Some(mime_type))])) |
+                (
+                    "zwp_primary_selection_source_v1",
+                    protocol::zwp_primary_selection_source_v1::REQ_OFFER,
+                    Some([Arg::String(Some(mime_type))]),
+                ) => {
+                    if let Some(mime_types) = self.selection_sources.get_mut(&packet.id) {
+                        mime_types.push(mime_type.clone());
+                    }
+                },
+                ("wl_data_source", protocol::wl_data_source::REQ_DESTROY, _) |
+                ("zwp_primary_selection_source_v1", protocol::zwp_primary_selection_source_v1::REQ_DESTROY, _) => {
+                    self.selection_sources.remove(&packet.id);
+                    clipboard.source_destroyed(
+                        SelectionKind::of(o.interface.name).unwrap(),
+                        self.client.conn_id,
+                        packet.id,
+                    );
+                },
+                (
+                    "wl_data_device_manager",
+                    protocol::wl_data_device_manager::REQ_GET_DATA_DEVICE,
+                    Some([Arg::NewId(device), _]),
+                ) | (
+                    "zwp_primary_selection_device_manager_v1",
+                    protocol::zwp_primary_selection_device_manager_v1::REQ_GET_DEVICE,
+                    Some([Arg::NewId(device), _]),
+                ) => {
+                    let kind = SelectionKind::of(o.interface.name).unwrap();
+                    self.selection_devices.insert(*device, kind);
+                    if config.clipboard_isolation(&self.client).is_some() {
+                        self.offer_selection(config, clipboard, *device, kind);
+                    }
+                },
+                ("wl_data_device", protocol::wl_data_device::REQ_RELEASE, _) |
+                ("zwp_primary_selection_device_v1", protocol::zwp_primary_selection_device_v1::REQ_DESTROY, _) => {
+                    self.selection_devices.remove(&packet.id);
+                    self.host_selections.remove(&packet.id);
+                    self.destroy_host_offers(packet.id, None);
+                },
+                (
+                    "wl_data_device",
+                    protocol::wl_data_device::REQ_SET_SELECTION,
+                    Some([Arg::Object(source), Arg::Uint(_)]),
+                ) | (
+                    "zwp_primary_selection_device_v1",
+                    protocol::zwp_primary_selection_device_v1::REQ_SET_SELECTION,
+                    Some([Arg::Object(source), Arg::Uint(_)]),
+                ) => {
+                    if config.clipboard_isolation(&self.client).is_some() {
+                        // Never copy to the host
+                        forward = false;
+                        let source = match *source {
+                            0 => None,
+                            source => Some(
+                                (
+                                    self.client.conn_id,
+                                    source,
+                                    self.selection_sources.get(&source).cloned().unwrap_or_default(),
+                                ),
+                            ),
+                        };
+                        if self.debug {
+                            eprintln!("Setting isolated selection to source {:?}", source);
+                        }
+                        clipboard.set(SelectionKind::of(o.interface.name).unwrap(), source);
+                    }
+                },
+                ("xdg_toplevel", protocol::xdg_toplevel::REQ_DESTROY, _) => {
+                    if let Some(decoration) = self.toplevels.remove(&packet.id).and_then(|t| t.decoration) {
+                        // The decoration has to go first
+                        send_before.push(
+                            build_packet(decoration.proxy, protocol::zxdg_toplevel_decoration_v1::REQ_DESTROY, &[]),
+                        );
+                    }
+                },
+                _ => { },
+            }
+        }
+
+        // Forward message with retractions/additions
+        for m in send_before {
+            if self.debug {
+                eprintln!("Sending synthetic request upstream: {:?}", m);
+            }
+            self.upstream.queue(&m, vec![])?;
+        }
+        if forward {
+            self.upstream.queue(&packet, fds)?;
+        }
+        for m in send_extra {
+            self.inject_request(m, vec![]);
+        }
+        return Ok(());
+    }
+
+    fn handle_event(
+        &mut self,
+        config: &Config,
+        clipboard: &mut Clipboard,
+        mut packet: Packet,
+        fds: Vec<OwnedFd>,
+    ) -> Result<(), String> {
+        if self.debug {
+            eprintln!("Received packet from upstream with {} ancillary FDs: {:?}", fds.len(), packet);
+        }
+
+        // Tracking and manipulation
+        let mut forward = true;
+        let upstream_id = packet.id;
+        let proxy = self.ids.is_proxy(upstream_id);
+        let downstream_id = self.ids.event_id(upstream_id);
+        let o = self.objects.get(upstream_id);
+        let parsed = o.is_some_and(|o| o.interface.events.get(packet.opcode as usize).is_some());
+        if !parsed && downstream_id.is_some() && !self.pass_opaque(config) {
+            return Err(
+                format!(
+                    "Can't forward unknown event {} from {}@{}, filterway creates objects of its own",
+                    packet.opcode,
+                    o.map(|o| o.interface.name).unwrap_or("untracked object"),
+                    upstream_id
+                ),
+            );
+        }
+        if let Some(o) = o {
+            if let Some(message) = o.interface.events.get(packet.opcode as usize) {
+                let mut message_args =
+                    proto::decode_args(
+                        &packet.body,
+                        &message.signature(),
+                        &mut fds.iter().map(|fd| fd.as_raw_fd()).collect::<VecDeque<_>>(),
+                    ).context(&format!("Error reading {}.{} event", o.interface.name, message.name))?;
+                for i in self.objects.track(upstream_id, o, message, &message_args) {
+                    if self.debug {
+                        eprintln!("Server created object with unknown interface [{}], not tracking", i);
+                    }
+                }
+                if proxy {
+                    forward = false;
+                    for arg in &message_args {
+                        if let Arg::NewId(id) | Arg::UntypedNewId { id, .. } = arg {
+                            self.ids.server_new(upstream_id, *id);
+                            if let Some(role) = self.proxies.get(&upstream_id).copied() {
+                                self.proxies.insert(*id, role);
+                            }
+                        }
+                    }
+                    self.handle_proxy_event(o, &packet, &message_args);
+                } else if config.clipboard_isolation(&self.client).is_some() &&
+                    self.hold_host_event(config, clipboard, upstream_id, o, packet.opcode, &message_args)? {
+                    forward = false;
+                } else if translate_event_args(&mut self.ids, upstream_id, &mut message_args) {
+                    packet.body.clear();
+                    proto::encode_args(&message_args, &mut packet.body, &mut vec![]).unwrap();
+                }
+                if message.destructor && upstream_id >= SERVER_ID_START {
+                    self.ids.remove(upstream_id);
+                    self.proxies.remove(&upstream_id);
+                }
+                match (o.interface.name, packet.opcode, message_args.as_slice()) {
+                    _ if proxy => { },
+                    // Ack delete
+                    ("wl_display", protocol::wl_display::EVT_DELETE_ID, [Arg::Uint(obj_id)]) => {
+                        self.objects.remove(*obj_id);
+                        self.proxies.remove(obj_id);
+                        match self.ids.event_id(*obj_id) {
+                            Some(downstream) => {
+                                if downstream != *obj_id {
+                                    packet.body.clear();
+                                    proto::write_arg_uint(&mut packet.body, downstream).unwrap();
+                                }
+                            },
+                            None => {
+                                forward = false;
+                            },
+                        }
+                        self.ids.remove(*obj_id);
+                    },
+                    (
+                        "wl_registry",
+                        protocol::wl_registry::EVT_GLOBAL,
+                        [Arg::Uint(name), Arg::String(Some(interface)), Arg::Uint(version)],
+                    ) => {
+                        if interface == protocol::zxdg_decoration_manager_v1::INTERFACE.name {
+                            self.decoration_global = Some(UpstreamGlobal {
+                                registry: upstream_id,
+                                name: *name,
+                                version: *version,
+                            });
+                        }
+                        let mut filter = config.global(interface, &self.client);
+                        // Unknown messages can't be passed through once filterway creates
+                        // objects of its own, so make sure there won't be any
+                        let isolation = config.clipboard_isolation(&self.client);
+                        if config.force_server_side_decorations(&self.client) || isolation.is_some() {
+                            match protocol::find_interface(interface) {
+                                Some(known) => {
+                                    filter.max_version =
+                                        Some(filter.max_version.map_or(known.version, |m| m.min(known.version)));
+                                },
+                                None => filter.hidden = true,
+                            }
+                        }
+                        if isolation.is_some() && UNISOLATED_INTERFACES.contains(&interface.as_str()) {
+                            filter.hidden = true;
+                        }
+                        if filter.hidden {
+                            forward = false;
+                            if self.debug {
+                                eprintln!("Hiding global {} ({})", interface, name);
+                            }
+                        } else {
+                            let mut advertise_version = *version;
+                            if let Some(max) = filter.max_version {
+                                if advertise_version > max {
+                                    advertise_version = max;
+                                    if self.debug {
+                                        eprintln!(
+                                            "Clamped global {} version {} to {}",
+                                            interface,
+                                            version,
+                                            advertise_version
+                                        );
+                                    }
+                                }
+                            }
+                            let advertise_name = self.globals.add(Global {
+                                upstream_name: *name,
+                                interface: interface.clone(),
+                                version: advertise_version,
+                                removed: false,
+                            });
+                            packet.body.clear();
+                            proto::encode_args(
+                                &[
+                                    Arg::Uint(advertise_name),
+                                    Arg::String(Some(interface.clone())),
+                                    Arg::Uint(advertise_version),
+                                ],
+                                &mut packet.body,
+                                &mut vec![],
+                            ).unwrap();
+                        }
+                    },
+                    ("wl_registry", protocol::wl_registry::EVT_GLOBAL_REMOVE, [Arg::Uint(name)]) => {
+                        if self.decoration_global.as_ref().is_some_and(|g| g.name == *name) {
+                            self.decoration_global = None;
+                        }
+                        match self.globals.remove(*name) {
+                            Some(advertise_name) => {
+                                packet.body.clear();
+                                proto::write_arg_uint(&mut packet.body, advertise_name).unwrap();
+                            },
+                            None => {
+                                forward = false;
+                            },
+                        }
+                    },
+                    _ => { },
+                }
+            } else if self.debug {
+                eprintln!("Unknown {} event opcode {}, forwarding as is", o.interface.name, packet.opcode);
+            }
+        }
+        match downstream_id {
+            Some(downstream) => packet.id = downstream,
+            None => forward = false,
+        }
+
+        // Forward messages
+        if packet.id == 1 && packet.opcode == protocol::wl_display::EVT_ERROR {
+            self.error_sent = true;
+        }
+        if forward {
+            self.downstream.queue(&packet, fds)?;
+        }
+        return Ok(());
+    }
+}
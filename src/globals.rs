@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct Global {
+    /// The global's name on the upstream connection
+    pub upstream_name: u32,
+    pub interface: String,
+    /// The version as advertised to the client, after any rewriting
+    pub version: u32,
+    /// The compositor sent `global_remove`. Binds that were already in flight are
+    /// still forwarded, like libwayland does, and the compositor decides what to do
+    /// with them.
+    pub removed: bool,
+}
+
+/// Globals advertised to the client on one connection.
+///
+/// Clients see globals under names assigned here rather than the compositor's, so
+/// hidden globals can't be reached by guessing names and the compositor's global
+/// list can't be probed.
+pub struct Globals {
+    /// By client-visible name, including removed globals
+    globals: HashMap<u32, Global>,
+    /// Upstream name to client-visible name, for globals that haven't been removed
+    downstream_names: HashMap<u32, u32>,
+    next_name: u32,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        return Self {
+            globals: HashMap::new(),
+            downstream_names: HashMap::new(),
+            next_name: 1,
+        };
+    }
+}
+
+impl Globals {
+    /// Make a global visible to the client, returning the client-visible name. Adding
+    /// an already visible global (like when the client creates another registry)
+    /// returns the same name.
+    pub fn add(&mut self, global: Global) -> u32 {
+        let name = match self.downstream_names.get(&global.upstream_name) {
+            Some(name) => *name,
+            None => {
+                let name = self.next_name;
+                self.next_name += 1;
+                self.downstream_names.insert(global.upstream_name, name);
+                name
+            },
+        };
+        self.globals.insert(name, global);
+        return name;
+    }
+
+    /// Mark a global removed by upstream name. Returns the client-visible name if the
+    /// global was visible. Its name isn't reused, so late binds still reach the right
+    /// global.
+    pub fn remove(&mut self, upstream_name: u32) -> Option<u32> {
+        let name = self.downstream_names.remove(&upstream_name)?;
+        if let Some(global) = self.globals.get_mut(&name) {
+            global.removed = true;
+        }
+        return Some(name);
+    }
+
+    /// Look up a global by client-visible name.
+    pub fn get(&self, name: u32) -> Option<&Global> {
+        return self.globals.get(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn global(upstream_name: u32, interface: &str) -> Global {
+        return Global {
+            upstream_name: upstream_name,
+            interface: interface.to_string(),
+            version: 1,
+            removed: false,
+        };
+    }
+
+    #[test]
+    fn renumbers() {
+        let mut globals = Globals::default();
+        assert_eq!(globals.add(global(7, "wl_compositor")), 1);
+        assert_eq!(globals.add(global(12, "wl_seat")), 2);
+        assert_eq!(globals.get(2).unwrap().upstream_name, 12);
+        assert!(globals.get(3).is_none());
+
+        // Another registry sees the same names
+        assert_eq!(globals.add(global(7, "wl_compositor")), 1);
+        assert_eq!(globals.add(global(13, "wl_shm")), 3);
+    }
+
+    #[test]
+    fn removed_globals_stay_bindable() {
+        let mut globals = Globals::default();
+        globals.add(global(7, "wl_compositor"));
+        globals.add(global(12, "wl_output"));
+        assert_eq!(globals.remove(12), Some(2));
+        assert_eq!(globals.remove(12), None);
+        assert_eq!(globals.remove(99), None);
+        let removed = globals.get(2).unwrap();
+        assert!(removed.removed);
+        assert_eq!(removed.upstream_name, 12);
+
+        // The name isn't reused, even if the compositor reuses its own
+        assert_eq!(globals.add(global(12, "wl_output")), 3);
+        assert!(globals.get(2).unwrap().removed);
+        assert!(!globals.get(3).unwrap().removed);
+    }
+}
@@ -0,0 +1,397 @@
+use {
+    crate::objects::SERVER_ID_START,
+    std::collections::{
+        BTreeSet,
+        HashMap,
+        HashSet,
+    },
+};
+
+/// Hands out ids in one range the way libwayland does: a new id must be a free
+/// slot or directly follow the highest id so far, or the peer rejects it.
+struct Allocator {
+    next: u32,
+    free: BTreeSet<u32>,
+}
+
+impl Allocator {
+    fn new(start: u32) -> Self {
+        return Self {
+            next: start,
+            free: BTreeSet::new(),
+        };
+    }
+
+    /// Allocate `preferred` if it's available, otherwise the lowest free id
+    fn alloc(&mut self, preferred: u32) -> u32 {
+        if preferred == self.next {
+            self.next += 1;
+            return preferred;
+        }
+        if self.free.remove(&preferred) {
+            return preferred;
+        }
+        if let Some(id) = self.free.pop_first() {
+            return id;
+        }
+        let id = self.next;
+        self.next += 1;
+        return id;
+    }
+
+    /// Allocate exactly `id` if it's free. Ids skipped over aren't freed: they're used
+    /// by objects created in messages filterway couldn't parse.
+    fn take(&mut self, id: u32) -> bool {
+        if id >= self.next {
+            self.next = id + 1;
+            return true;
+        }
+        return self.free.remove(&id);
+    }
+
+    fn release(&mut self, id: u32) {
+        if id < self.next {
+            self.free.insert(id);
+        }
+    }
+}
+
+/// Translates object ids between the client's connection (downstream) and the
+/// compositor connection (upstream). Objects filterway creates only exist on one
+/// side, so the two sides' ids diverge once there are any.
+///
+/// Until then ids are the same on both sides, so messages filterway can't parse
+/// (and so can't rewrite) can be passed through. Objects created by those messages
+/// aren't known here, so afterwards filterway can't create objects of its own
+/// without risking reusing their ids. Whichever happens first on a connection
+/// rules out the other.
+pub struct IdMap {
+    to_upstream: HashMap<u32, u32>,
+    to_downstream: HashMap<u32, u32>,
+    /// Upstream ids of objects owned by filterway, including ones the server created
+    /// in events to them
+    proxy: HashSet<u32>,
+    /// Downstream ids of objects filterway handles on the client's side, which don't
+    /// exist upstream
+    local: HashSet<u32>,
+    /// Client-allocated ids on the upstream connection
+    upstream_ids: Allocator,
+    /// Server-allocated ids on the downstream connection
+    downstream_ids: Allocator,
+    /// Upstream ids of server-created objects that were destroyed, until the server
+    /// reuses them. Events already on the way for them are dropped.
+    zombies: HashSet<u32>,
+    /// Filterway has created objects, so ids may differ between the sides
+    diverged: bool,
+    /// Messages filterway couldn't parse have been passed through
+    opaque: bool,
+}
+
+impl Default for IdMap {
+    fn default() -> Self {
+        return Self {
+            to_upstream: HashMap::from([(1, 1)]),
+            to_downstream: HashMap::from([(1, 1)]),
+            proxy: HashSet::new(),
+            local: HashSet::new(),
+            upstream_ids: Allocator::new(2),
+            downstream_ids: Allocator::new(SERVER_ID_START),
+            zombies: HashSet::new(),
+            diverged: false,
+            opaque: false,
+        };
+    }
+}
+
+impl IdMap {
+    /// Whether filterway can create objects of its own (see above).
+    pub fn can_create(&self) -> bool {
+        return !self.opaque;
+    }
+
+    /// Record passing through a message filterway couldn't parse. Returns false if
+    /// that's not safe (see above) and the message must be refused.
+    pub fn pass_opaque(&mut self) -> bool {
+        if self.diverged {
+            return false;
+        }
+        self.opaque = true;
+        return true;
+    }
+
+    pub fn is_proxy(&self, upstream: u32) -> bool {
+        return self.proxy.contains(&upstream);
+    }
+
+    /// The upstream id for an id in a request. Objects created by messages
+    /// filterway couldn't parse aren't mapped; their ids are passed through if they
+    /// don't refer to a different object upstream.
+    pub fn request_id(&self, downstream: u32) -> Option<u32> {
+        if let Some(upstream) = self.to_upstream.get(&downstream) {
+            return Some(*upstream);
+        }
+        if self.to_downstream.contains_key(&downstream) || self.proxy.contains(&downstream) {
+            return None;
+        }
+        return Some(downstream);
+    }
+
+    /// The downstream id for an id in an event, or `None` if the object is hidden
+    /// from the client or was destroyed.
+    pub fn event_id(&self, upstream: u32) -> Option<u32> {
+        if let Some(downstream) = self.to_downstream.get(&upstream) {
+            return Some(*downstream);
+        }
+        if self.to_upstream.contains_key(&upstream) || self.proxy.contains(&upstream) ||
+            self.local.contains(&upstream) || self.zombies.contains(&upstream) {
+            return None;
+        }
+        return Some(upstream);
+    }
+
+    /// Map an object the client created. Returns the upstream id, or `None` if the id
+    /// is invalid or already in use.
+    pub fn client_new(&mut self, downstream: u32) -> Option<u32> {
+        if downstream == 0 || downstream >= SERVER_ID_START || self.to_upstream.contains_key(&downstream) ||
+            self.local.contains(&downstream) {
+            return None;
+        }
+        let upstream = if !self.diverged && self.upstream_ids.take(downstream) {
+            downstream
+        } else {
+            self.diverged = true;
+            self.upstream_ids.alloc(downstream)
+        };
+        self.to_upstream.insert(downstream, upstream);
+        self.to_downstream.insert(upstream, downstream);
+        return Some(upstream);
+    }
+
+    /// Reserve an id the client allocated for an object filterway handles itself.
+    /// Returns false if the id is invalid or already in use. Only for use if
+    /// `can_create`.
+    pub fn client_local(&mut self, downstream: u32) -> bool {
+        if downstream == 0 || downstream >= SERVER_ID_START || self.to_upstream.contains_key(&downstream) {
+            return false;
+        }
+        if !self.local.insert(downstream) {
+            return false;
+        }
+        self.diverged = true;
+        return true;
+    }
+
+    /// Allocate a server id for an object filterway creates on the client's side. Only
+    /// for use if `can_create`.
+    pub fn server_local(&mut self) -> u32 {
+        self.diverged = true;
+        let downstream = self.downstream_ids.alloc(0);
+        self.local.insert(downstream);
+        return downstream;
+    }
+
+    pub fn remove_local(&mut self, downstream: u32) {
+        if self.local.remove(&downstream) && downstream >= SERVER_ID_START {
+            self.downstream_ids.release(downstream);
+        }
+    }
+
+    /// Allocate an upstream id for an object filterway creates. Only for use if
+    /// `can_create`.
+    pub fn proxy_new(&mut self) -> u32 {
+        self.diverged = true;
+        let upstream = self.upstream_ids.alloc(0);
+        self.proxy.insert(upstream);
+        return upstream;
+    }
+
+    /// Map an object the server created in an event to `parent`. Returns the
+    /// downstream id, or `None` if the parent is hidden from the client (so the new
+    /// object is too).
+    pub fn server_new(&mut self, parent: u32, upstream: u32) -> Option<u32> {
+        self.zombies.remove(&upstream);
+        if self.proxy.contains(&parent) {
+            self.proxy.insert(upstream);
+            return None;
+        }
+        let downstream = if !self.diverged && self.downstream_ids.take(upstream) {
+            upstream
+        } else {
+            self.diverged = true;
+            self.downstream_ids.alloc(upstream)
+        };
+        self.to_upstream.insert(downstream, upstream);
+        self.to_downstream.insert(upstream, downstream);
+        return Some(downstream);
+    }
+
+    /// Hide an object the server created from the client instead of mapping it with
+    /// `server_new`; its events go to filterway. Only for use if `can_create`.
+    pub fn server_proxy(&mut self, upstream: u32) {
+        self.zombies.remove(&upstream);
+        self.diverged = true;
+        self.proxy.insert(upstream);
+    }
+
+    /// Show the client an object hidden with `server_proxy`. Returns the downstream
+    /// id, which the client has to be told about in a new id argument.
+    pub fn reveal(&mut self, upstream: u32) -> u32 {
+        self.proxy.remove(&upstream);
+        let downstream = self.downstream_ids.alloc(upstream);
+        self.to_upstream.insert(downstream, upstream);
+        self.to_downstream.insert(upstream, downstream);
+        return downstream;
+    }
+
+    /// Forget an object once its id is free to reuse on both sides: after `delete_id`
+    /// for client-allocated ids, or after the destructor for server-allocated ones
+    /// (which stay zombies until reused).
+    pub fn remove(&mut self, upstream: u32) {
+        self.proxy.remove(&upstream);
+        if let Some(downstream) = self.to_downstream.remove(&upstream) {
+            self.to_upstream.remove(&downstream);
+            if downstream >= SERVER_ID_START {
+                self.downstream_ids.release(downstream);
+            }
+        }
+        if upstream < SERVER_ID_START {
+            self.upstream_ids.release(upstream);
+        } else {
+            self.zombies.insert(upstream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocator_stays_dense() {
+        let mut ids = Allocator::new(2);
+        assert_eq!(ids.alloc(0), 2);
+        assert_eq!(ids.alloc(9), 3);
+        assert_eq!(ids.alloc(4), 4);
+        ids.release(3);
+        assert_eq!(ids.alloc(9), 3);
+        ids.release(2);
+        ids.release(4);
+        assert_eq!(ids.alloc(4), 4);
+        assert_eq!(ids.alloc(0), 2);
+        assert_eq!(ids.alloc(0), 5);
+    }
+
+    #[test]
+    fn allocator_take_skips_unknown_ids() {
+        let mut ids = Allocator::new(2);
+        assert!(ids.take(2));
+        // 3 and 4 were created by messages filterway couldn't parse
+        assert!(ids.take(5));
+        assert!(!ids.take(5));
+        assert_eq!(ids.alloc(0), 6);
+        ids.release(5);
+        assert!(ids.take(5));
+        assert!(!ids.take(5));
+    }
+
+    #[test]
+    fn identity_until_diverged() {
+        let mut ids = IdMap::default();
+        assert_eq!(ids.client_new(2), Some(2));
+        assert!(ids.pass_opaque());
+
+        // The unparsed message created 3
+        assert_eq!(ids.client_new(4), Some(4));
+        assert_eq!(ids.request_id(3), Some(3));
+        assert_eq!(ids.event_id(3), Some(3));
+        assert_eq!(ids.server_new(4, 0xff000001), Some(0xff000001));
+        assert_eq!(ids.event_id(0xff000001), Some(0xff000001));
+        assert!(!ids.can_create());
+    }
+
+    #[test]
+    fn proxy_ids_are_translated() {
+        let mut ids = IdMap::default();
+        assert_eq!(ids.client_new(2), Some(2));
+        assert!(ids.can_create());
+        let proxy = ids.proxy_new();
+        assert_eq!(proxy, 3);
+        assert!(!ids.pass_opaque());
+        assert_eq!(ids.client_new(3), Some(4));
+        assert_eq!(ids.request_id(3), Some(4));
+        assert_eq!(ids.event_id(4), Some(3));
+        assert_eq!(ids.event_id(proxy), None);
+        assert!(ids.is_proxy(proxy));
+
+        // Objects the server creates for proxies are hidden too
+        assert_eq!(ids.server_new(proxy, 0xff000000), None);
+        assert!(ids.is_proxy(0xff000000));
+        assert_eq!(ids.event_id(0xff000000), None);
+        assert_eq!(ids.server_new(4, 0xff000001), Some(0xff000000));
+        assert_eq!(ids.event_id(0xff000001), Some(0xff000000));
+
+        // Ids are reused once deleted
+        ids.remove(4);
+        assert_eq!(ids.request_id(3), None);
+        assert_eq!(ids.client_new(3), Some(4));
+        ids.remove(proxy);
+        assert_eq!(ids.client_new(5), Some(5));
+        assert_eq!(ids.proxy_new(), 3);
+    }
+
+    #[test]
+    fn local_ids() {
+        let mut ids = IdMap::default();
+        assert_eq!(ids.client_new(2), Some(2));
+        assert!(ids.client_local(3));
+        assert!(!ids.client_local(3));
+        assert!(!ids.client_local(2));
+        assert_eq!(ids.client_new(3), None);
+        assert_eq!(ids.event_id(3), None);
+
+        // The compositor never saw 3, so the next client object takes its place
+        assert_eq!(ids.client_new(4), Some(3));
+        let offer = ids.server_local();
+        assert_eq!(offer, SERVER_ID_START);
+        assert_eq!(ids.event_id(offer), None);
+        ids.remove_local(offer);
+        ids.remove_local(3);
+        assert_eq!(ids.server_local(), SERVER_ID_START);
+        assert_eq!(ids.client_new(3), Some(4));
+    }
+
+    #[test]
+    fn hidden_server_objects() {
+        let mut ids = IdMap::default();
+        assert_eq!(ids.client_new(2), Some(2));
+        ids.server_proxy(SERVER_ID_START);
+        assert!(!ids.pass_opaque());
+        assert_eq!(ids.event_id(SERVER_ID_START), None);
+        assert_eq!(ids.server_new(2, SERVER_ID_START + 1), Some(SERVER_ID_START));
+        assert_eq!(ids.reveal(SERVER_ID_START), SERVER_ID_START + 1);
+        assert!(!ids.is_proxy(SERVER_ID_START));
+        assert_eq!(ids.event_id(SERVER_ID_START), Some(SERVER_ID_START + 1));
+        assert_eq!(ids.request_id(SERVER_ID_START + 1), Some(SERVER_ID_START));
+    }
+
+    #[test]
+    fn destroyed_server_objects() {
+        let mut ids = IdMap::default();
+        assert_eq!(ids.client_new(2), Some(2));
+        assert_eq!(ids.server_new(2, SERVER_ID_START), Some(SERVER_ID_START));
+        ids.remove(SERVER_ID_START);
+        assert_eq!(ids.event_id(SERVER_ID_START), None);
+        assert_eq!(ids.server_new(2, SERVER_ID_START), Some(SERVER_ID_START));
+        assert_eq!(ids.event_id(SERVER_ID_START), Some(SERVER_ID_START));
+    }
+
+    #[test]
+    fn rejects_invalid_new_ids() {
+        let mut ids = IdMap::default();
+        assert_eq!(ids.client_new(0), None);
+        assert_eq!(ids.client_new(1), None);
+        assert_eq!(ids.client_new(SERVER_ID_START), None);
+        assert_eq!(ids.client_new(2), Some(2));
+        assert_eq!(ids.client_new(2), None);
+    }
+}
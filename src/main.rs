@@ -1,18 +1,28 @@
 #![feature(unix_socket_ancillary_data)]
+#![feature(peer_credentials_unix_socket)]
+// The code base writes out `return` and `field: field`
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
 
 use {
     aargvark::{
+        traits_impls::NotFlag,
         vark,
         Aargvark,
     },
-    proto::read_arg_string,
+    client::ClientInfo,
+    clipboard::Clipboard,
+    config::{
+        Action,
+        ClipboardIsolation,
+        Config,
+        Match,
+        Rule,
+    },
+    connection::Connection,
+    template::Template,
     rustix::{
-        fd::{
-            AsFd,
-            FromRawFd,
-            OwnedFd,
-            RawFd,
-        },
+        event::epoll,
+        fd::AsFd,
         fs::{
             flock,
             OpenOptionsExt,
@@ -28,14 +38,7 @@ use {
             remove_file,
             File,
         },
-        io::{
-            Cursor,
-            IoSlice,
-            IoSliceMut,
-        },
         os::unix::net::{
-            AncillaryData,
-            SocketAncillary,
             UnixListener,
             UnixStream,
         },
@@ -43,13 +46,27 @@ use {
         process::exit,
         sync::{
             Arc,
-            Mutex,
+            RwLock,
         },
         thread::spawn,
+        time::{
+            Duration,
+            Instant,
+        },
     },
 };
 
+pub mod client;
+pub mod clipboard;
+pub mod config;
+pub mod connection;
+pub mod globals;
+pub mod ids;
+pub mod objects;
 pub mod proto;
+pub mod protocol;
+pub mod stream;
+pub mod template;
 
 #[derive(Aargvark, Clone)]
 struct Args {
@@ -69,6 +86,30 @@ struct Args {
     title: Option<String>,
     /// Prefix the title instead of replacing
     prefix_title: Option<()>,
+    /// Make the compositor draw decorations for all xdg toplevels, whatever the
+    /// client asks for
+    force_server_side_decorations: Option<()>,
+    /// Give clients a clipboard and primary selection shared only among clients of
+    /// this filterway instance
+    #[vark(flag = "--isolate-clipboard")]
+    isolate_clipboard: Option<ClipboardIsolation>,
+    /// Lower the advertised versions of globals to the highest versions in the
+    /// protocol files filterway was built with, so clients never bind a version
+    /// filterway doesn't understand
+    clamp_versions: Option<()>,
+    /// Highest version to advertise for specific globals, like `xdg_wm_base=5`.
+    /// Clients binding a higher version are disconnected.
+    #[vark(flag = "--max-version")]
+    max_version: Option<HashMap<String, u32>>,
+    /// Don't show clients these globals (like `zwlr_screencopy_manager_v1`)
+    #[vark(flag = "--hide-global")]
+    hide_global: Option<Vec<NotFlag>>,
+    /// Only show clients these globals; others are hidden
+    #[vark(flag = "--allow-global")]
+    allow_global: Option<Vec<NotFlag>>,
+    /// JSON file with a list of rules, applied after rules from the above flags.
+    /// Send filterway `SIGHUP` to reload it.
+    config: Option<PathBuf>,
     /// Print debug messages
     debug: Option<()>,
 }
@@ -86,60 +127,209 @@ impl<T, E: Display> Errorize<T> for Result<T, E> {
     }
 }
 
-struct AncillaryReader<'a> {
-    reader: &'a UnixStream,
-    ancillary_mem: &'a mut [u8],
-    fds: &'a mut Vec<RawFd>,
-}
-
-impl<'a> std::io::Read for AncillaryReader<'a> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut ancillary = SocketAncillary::new(&mut self.ancillary_mem);
-        let res = self.reader.recv_vectored_with_ancillary(&mut [IoSliceMut::new(buf)], &mut ancillary);
-        if ancillary.truncated() {
-            panic!("Ancillary buffer too small");
-        }
-        for m in ancillary.messages() {
-            let Ok(AncillaryData::ScmRights(m)) = m else {
-                continue;
-            };
-            self.fds.extend(m);
+/// Rules equivalent to the command line flags.
+fn args_rules(args: &Args) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+    if let Some(allow) = &args.allow_global {
+        rules.push(Rule {
+            match_: Match::default(),
+            actions: vec![Action::HideGlobal],
+        });
+        for interface in allow {
+            rules.push(Rule {
+                match_: Match {
+                    interface: Some(interface.0.clone()),
+                    ..Default::default()
+                },
+                actions: vec![Action::ShowGlobal],
+            });
         }
-        return res;
     }
+    for interface in args.hide_global.iter().flatten() {
+        rules.push(Rule {
+            match_: Match {
+                interface: Some(interface.0.clone()),
+                ..Default::default()
+            },
+            actions: vec![Action::HideGlobal],
+        });
+    }
+    for (interface, version) in args.max_version.iter().flatten() {
+        rules.push(Rule {
+            match_: Match {
+                interface: Some(interface.clone()),
+                ..Default::default()
+            },
+            actions: vec![Action::MaxVersion(*version)],
+        });
+    }
+    if args.clamp_versions.is_some() {
+        rules.push(Rule {
+            match_: Match::default(),
+            actions: vec![Action::ClampVersion],
+        });
+    }
+    if let Some(app_id) = &args.app_id {
+        rules.push(Rule {
+            match_: Match::default(),
+            actions: vec![if args.prefix.is_some() {
+                Action::PrefixAppId(Template::parse(app_id)?)
+            } else {
+                Action::SetAppId(Template::parse(app_id)?)
+            }],
+        });
+    }
+    if let Some(title) = &args.title {
+        rules.push(Rule {
+            match_: Match::default(),
+            actions: vec![if args.prefix_title.is_some() {
+                Action::PrefixTitle(Template::parse(title)?)
+            } else {
+                Action::SetTitle(Template::parse(title)?)
+            }],
+        });
+    }
+    if args.force_server_side_decorations.is_some() {
+        rules.push(Rule {
+            match_: Match::default(),
+            actions: vec![Action::ForceServerSideDecorations],
+        });
+    }
+    if let Some(isolation) = args.isolate_clipboard {
+        rules.push(Rule {
+            match_: Match::default(),
+            actions: vec![Action::IsolateClipboard(isolation)],
+        });
+    }
+    return Ok(rules);
 }
 
-struct AncillaryWriter<'a, 'b> {
-    writer: &'a UnixStream,
-    ancillary: SocketAncillary<'b>,
+fn load_config(args: &Args) -> Result<Config, String> {
+    let mut config = Config { rules: args_rules(args)? };
+    if let Some(path) = &args.config {
+        config.rules.extend(Config::load(path)?.rules);
+    }
+    return Ok(config);
 }
 
-impl<'a, 'b> AncillaryWriter<'a, 'b> {
-    fn new(writer: &'a UnixStream, ancillary_mem: &'b mut [u8], fds: &Vec<RawFd>) -> Self {
-        let mut ancillary = SocketAncillary::new(ancillary_mem);
-        ancillary.add_fds(fds.as_ref());
-        return Self {
-            writer: writer,
-            ancillary: ancillary,
-        };
+fn sighup_set() -> libc::sigset_t {
+    unsafe {
+        let mut set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        return set;
     }
 }
 
-impl<'a, 'b> std::io::Write for AncillaryWriter<'a, 'b> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let res = self.writer.send_vectored_with_ancillary(&mut [IoSlice::new(buf)], &mut self.ancillary);
-        self.ancillary.clear();
-        return res;
-    }
+/// Reload the config on `SIGHUP`. New messages on existing connections use the new
+/// rules. `SIGHUP` must be blocked in every other thread (threads inherit the mask,
+/// so block it before spawning any).
+fn spawn_config_reloader(args: Args, config: Arc<RwLock<Arc<Config>>>) {
+    spawn(move || {
+        let set = sighup_set();
+        loop {
+            let mut signal = 0;
+            if unsafe {
+                libc::sigwait(&set, &mut signal)
+            } != 0 {
+                continue;
+            }
+            let new_config = match load_config(&args) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Warning, failed to reload config, keeping previous rules: {}", e);
+                    continue;
+                },
+            };
+            let mut config = config.write().unwrap();
+            let mut changed = false;
+            for rule in &config.rules {
+                if !new_config.rules.contains(rule) {
+                    eprintln!("Config reload: removed rule {:?}", rule);
+                    changed = true;
+                }
+            }
+            for rule in &new_config.rules {
+                if !config.rules.contains(rule) {
+                    eprintln!("Config reload: added rule {:?}", rule);
+                    changed = true;
+                }
+            }
+            if !changed && new_config != **config {
+                eprintln!("Config reload: rules reordered");
+                changed = true;
+            }
+            if !changed {
+                eprintln!("Config reload: no changes");
+            }
+            *config = Arc::new(new_config);
+        }
+    });
+}
+
+/// Epoll token for the downstream listener; connection sockets use `conn_id * 2`
+/// (downstream) and `conn_id * 2 + 1` (upstream) with ids starting at 1.
+const LISTENER_TOKEN: u64 = 0;
+
+/// How long to stop accepting connections after an error like running out of fds,
+/// unless a connection closes first
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+fn listen(epoll: &impl AsFd, listener: &UnixListener) -> Result<(), String> {
+    return epoll::add(
+        epoll,
+        listener,
+        epoll::EventData::new_u64(LISTENER_TOKEN),
+        epoll::EventFlags::IN,
+    ).context("Error adding downstream listener to epoll");
+}
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        return self.writer.flush();
+/// Pass messages between connections sharing an isolated clipboard, closing any
+/// connections that fail.
+fn deliver_clipboard(
+    connections: &mut HashMap<u64, Connection>,
+    clipboard: &mut Clipboard,
+    config: &Config,
+    debug: bool,
+) {
+    loop {
+        if clipboard.outbox.is_empty() && clipboard.changed.is_empty() {
+            return;
+        }
+        for (id, delivery) in std::mem::take(&mut clipboard.outbox) {
+            if let Some(conn) = connections.get_mut(&id) {
+                conn.deliver(delivery);
+            }
+        }
+        for kind in std::mem::take(&mut clipboard.changed) {
+            for conn in connections.values_mut() {
+                conn.offer_selections(config, clipboard, kind);
+            }
+        }
+        let mut closed = vec![];
+        for (id, conn) in connections.iter_mut() {
+            if let Err(e) = conn.flush() {
+                if debug {
+                    eprintln!("Connection {} closed while sending clipboard messages: {}", id, e);
+                }
+                closed.push(*id);
+            }
+        }
+        for id in closed {
+            connections.remove(&id);
+            clipboard.connection_closed(id);
+        }
     }
 }
 
 fn main() {
     fn inner() -> Result<(), String> {
         let args = vark::<Args>();
+        let config = Arc::new(RwLock::new(Arc::new(load_config(&args)?)));
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_BLOCK, &sighup_set(), std::ptr::null_mut());
+        }
+        spawn_config_reloader(args.clone(), config.clone());
         let lock_path = args.downstream.with_extension("lock");
         let filelock =
             File::options()
@@ -177,355 +367,123 @@ fn main() {
             };
         }
 
-        // Listen for connections
-        loop {
-            let (downstream, _) = downstream.accept().context("Error accepting downstream connection")?;
-            let upstream = UnixStream::connect(&args.upstream).context("Error creating upstream connection")?;
+        // Listen for connections and proxy them all from this thread
+        downstream.set_nonblocking(true).context("Error making downstream listener non-blocking")?;
+        let epoll = epoll::create(epoll::CreateFlags::CLOEXEC).context("Error creating epoll")?;
+        listen(&epoll, &downstream)?;
+        let mut connections = HashMap::<u64, Connection>::new();
+        let mut clipboard = Clipboard::default();
+        let mut conn_id = 0u64;
+        let mut events = epoll::EventVec::with_capacity(64);
 
-            #[derive(Clone, Copy, Debug)]
-            enum ObjType {
-                Display,
-                Registry,
-                XdgWmBase {
-                    ver: u32,
-                },
-                XdgSurface {
-                    ver: u32,
-                },
-                XdgToplevel {
-                    ver: u32,
-                },
+        // While accepting is paused: when to retry, and the number of connections at the
+        // time (retry early if one closes)
+        let mut accept_paused: Option<(Instant, usize)> = None;
+        loop {
+            let mut timeout = -1;
+            if let Some((retry, count)) = accept_paused {
+                let now = Instant::now();
+                if now >= retry || connections.len() < count {
+                    listen(&epoll, &downstream)?;
+                    accept_paused = None;
+                } else {
+                    timeout = (retry - now).as_millis() as i32 + 1;
+                }
             }
-
-            let objects = Arc::new(Mutex::new(HashMap::new()));
-            objects.lock().unwrap().insert(1, ObjType::Display);
-            let xdgwmbase_type_id = Arc::new(Mutex::new(None));
-            spawn({
-                let downstream = downstream.try_clone().unwrap();
-                let mut upstream = upstream.try_clone().unwrap();
-                let objects = objects.clone();
-                let xdgwmbase_type_id = xdgwmbase_type_id.clone();
-                let args = args.clone();
-                move || {
-                    let _defer = defer::defer({
-                        let downstream = downstream.try_clone().unwrap();
-                        let upstream = upstream.try_clone().unwrap();
-                        move || {
-                            _ = downstream.shutdown(std::net::Shutdown::Both);
-                            _ = upstream.shutdown(std::net::Shutdown::Both);
-                        }
-                    });
-                    match (|| -> Result<(), String> {
-                        let mut send_extra = vec![];
-                        let mut ancillary_mem = [0u8; 128];
-                        let mut ancillary_accum = vec![];
-                        loop {
-                            // Wait for next message
-                            let Some(mut packet) = proto::read_packet(&mut AncillaryReader {
-                                reader: &downstream,
-                                ancillary_mem: &mut ancillary_mem,
-                                fds: &mut ancillary_accum,
-                            }).context("Error reading message")? else {
+            match epoll::wait(&epoll, &mut events, timeout) {
+                Ok(_) => { },
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => return Err(format!("Error waiting for events: {}", e)),
+            }
+            for event in &events {
+                let token = event.data.u64();
+                if token == LISTENER_TOKEN {
+                    loop {
+                        let downstream = match downstream.accept() {
+                            Ok((downstream, _)) => downstream,
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                            Err(e) if matches!(
+                                e.kind(),
+                                std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::Interrupted
+                            ) => continue,
+                            Err(e) => {
+                                // Like running out of fds. The listener stays readable, so stop
+                                // listening for a while rather than spin.
+                                eprintln!("Warning, error accepting downstream connection, pausing: {}", e);
+                                epoll::delete(&epoll, &downstream)
+                                    .context("Error removing downstream listener from epoll")?;
+                                accept_paused = Some((Instant::now() + ACCEPT_RETRY, connections.len()));
                                 break;
-                            };
-
-                            // Track and prepare manipulations
-                            {
-                                let mut objects = objects.lock().unwrap();
-                                let o = objects.get(&packet.id).cloned();
-                                if args.debug.is_some() {
-                                    eprintln!(
-                                        "Received packet from downstream for tracked object {:?} with {} ancillary FDs: {:?}",
-                                        o,
-                                        ancillary_accum.len(),
-                                        packet
-                                    );
-                                }
-                                if let Some(o) = o {
-                                    match o {
-                                        ObjType::Display => {
-                                            match packet.opcode {
-                                                // Get registry
-                                                1 => {
-                                                    let mut cursor = Cursor::new(&packet.body);
-                                                    let obj_id =
-                                                        proto::read_arg_uint(
-                                                            &mut cursor,
-                                                        ).context("Error reading registry id")?;
-                                                    objects.insert(obj_id, ObjType::Registry);
-                                                },
-                                                _ => { },
-                                            }
-                                        },
-                                        ObjType::Registry => {
-                                            match packet.opcode {
-                                                // Bind
-                                                0 => {
-                                                    let mut cursor = Cursor::new(&packet.body);
-                                                    let obj_type_id =
-                                                        proto::read_arg_uint(
-                                                            &mut cursor,
-                                                        ).context("Error/eof reading bind object type id")?;
-
-                                                    // Arbitrary snowflake magic param - interface name
-                                                    proto::read_arg_string(&mut cursor).context("Error reading bind message type string")?;
-
-                                                    // Arbitrary snowflake magic param - version
-                                                    let version = proto::read_arg_uint(&mut cursor).context("Error reading bind message version")?;
-                                                    let obj_id =
-                                                        proto::read_arg_uint(
-                                                            &mut cursor,
-                                                        ).context("Error/eof reading bind object id")?;
-                                                    if let Some((want_type_id, _version)) =
-                                                        *xdgwmbase_type_id.lock().unwrap() {
-                                                        if obj_type_id == want_type_id {
-                                                            objects.insert(obj_id, ObjType::XdgWmBase {
-                                                                // prefer the magic param version because it's nearer to the use location...
-                                                                ver: version,
-                                                            });
-                                                        }
-                                                    }
-                                                },
-                                                _ => { },
-                                            }
-                                        },
-                                        ObjType::XdgWmBase { ver } => {
-                                            match ver {
-                                                0 ..= 6 => match packet.opcode {
-                                                    // Get surface
-                                                    2 => {
-                                                        let mut cursor = Cursor::new(&packet.body);
-                                                        let obj_id =
-                                                            proto::read_arg_uint(
-                                                                &mut cursor,
-                                                            ).context("Error reading xdg wm base create surface id")?;
-                                                        objects.insert(obj_id, ObjType::XdgSurface { ver: ver });
-                                                    },
-                                                    _ => (),
-                                                },
-                                                _ => panic!(
-                                                    "Unsupported xdg_wm_base object version {}_{}_{}_{}",
-                                                    ver,
-                                                    ver,
-                                                    ver,
-                                                    ver
-                                                ),
-                                            }
-                                        },
-                                        ObjType::XdgSurface { ver } => {
-                                            match ver {
-                                                0 ..= 6 => match packet.opcode {
-                                                    // Create toplevel
-                                                    1 => {
-                                                        let mut cursor = Cursor::new(&packet.body);
-                                                        let obj_id =
-                                                            proto::read_arg_uint(
-                                                                &mut cursor,
-                                                            ).context(
-                                                                "Error reading xdg surface create toplevel id",
-                                                            )?;
-                                                        objects.insert(obj_id, ObjType::XdgToplevel { ver: ver });
-                                                    },
-                                                    _ => (),
-                                                },
-                                                _ => panic!("Unsupported xdg_surface object version {}", ver),
-                                            }
-                                        },
-                                        ObjType::XdgToplevel { ver } => {
-                                            match ver {
-                                                0 ..= 6 => match packet.opcode {
-                                                    // set_title
-                                                    2 => {
-                                                        if let Some(title) = &args.title {
-                                                            let read_title =
-                                                                read_arg_string(
-                                                                    &mut packet.body.as_slice(),
-                                                                ).context("Error reading app id message body")?;
-                                                            packet.body.clear();
-                                                            proto::write_arg_string(
-                                                                &mut packet.body,
-                                                                if args.prefix_title.is_some() {
-                                                                    format!(
-                                                                        "{}{}",
-                                                                        title,
-                                                                        read_title.unwrap_or_default()
-                                                                    )
-                                                                } else {
-                                                                    title.clone()
-                                                                },
-                                                            ).unwrap();
-                                                            if args.debug.is_some() {
-                                                                eprintln!(
-                                                                    "Modified title; new message: {:?}",
-                                                                    packet
-                                                                );
-                                                            }
-                                                        }
-                                                    },
-                                                    // set_app_id
-                                                    3 => {
-                                                        if let Some(app_id) = &args.app_id {
-                                                            let read_app_id =
-                                                                read_arg_string(
-                                                                    &mut packet.body.as_slice(),
-                                                                ).context("Error reading app id message body")?;
-                                                            packet.body.clear();
-                                                            proto::write_arg_string(
-                                                                &mut packet.body,
-                                                                if args.prefix.is_some() {
-                                                                    format!(
-                                                                        "{}{}",
-                                                                        app_id,
-                                                                        read_app_id.unwrap_or_default()
-                                                                    )
-                                                                } else {
-                                                                    app_id.clone()
-                                                                },
-                                                            ).unwrap();
-                                                            if args.debug.is_some() {
-                                                                eprintln!(
-                                                                    "Modified app id; new message: {:?}",
-                                                                    packet
-                                                                );
-                                                            }
-                                                        }
-                                                    },
-                                                    _ => (),
-                                                },
-                                                _ => panic!("Unsupported xdg_toplevel object version {}", ver),
-                                            }
-                                        },
-                                    }
-                                }
-                            }
-
-                            // Forward message with retractions/additions
-                            proto::write_packet(
-                                &mut AncillaryWriter::new(&mut upstream, &mut ancillary_mem, &ancillary_accum),
-                                &packet,
-                            ).context("Error writing message")?;
-                            for fd in ancillary_accum.drain(..) {
-                                drop(unsafe {
-                                    OwnedFd::from_raw_fd(fd)
-                                });
-                            }
-                            for m in send_extra.drain(..) {
-                                if args.debug.is_some() {
-                                    eprintln!("Sending synthetic request upstream: {:?}", m);
-                                }
-                                proto::write_packet(&mut upstream, &m).context("Error writing message")?;
-                            }
-                        }
-                        return Ok(());
-                    })() {
-                        Ok(_) => { },
-                        Err(e) => {
-                            eprintln!("Warning, client->server thread exiting with error: {}", e);
-                        },
-                    }
-                }
-            });
-            spawn({
-                let mut downstream = downstream.try_clone().unwrap();
-                let mut upstream = upstream.try_clone().unwrap();
-                let objects = objects.clone();
-                move || {
-                    let _defer = defer::defer({
-                        let downstream = downstream.try_clone().unwrap();
-                        let upstream = upstream.try_clone().unwrap();
-                        move || {
-                            _ = downstream.shutdown(std::net::Shutdown::Both);
-                            _ = upstream.shutdown(std::net::Shutdown::Both);
+                            },
+                        };
+                        conn_id += 1;
+                        let client = ClientInfo::collect(conn_id, &args.downstream, &downstream);
+                        if args.debug.is_some() {
+                            eprintln!("Accepted downstream connection: {:?}", client);
                         }
-                    });
-                    match (|| -> Result<(), String> {
-                        let mut ancillary_mem = [0u8; 128];
-                        let mut ancillary_accum = vec![];
-                        let mut cache_reg_id = None;
-                        loop {
-                            // Read next packet
-                            let Some(packet) = proto::read_packet(&mut AncillaryReader {
-                                reader: &mut upstream,
-                                ancillary_mem: &mut ancillary_mem,
-                                fds: &mut ancillary_accum,
-                            }).context("Error reading message")? else {
-                                break;
-                            };
-                            if args.debug.is_some() {
-                                eprintln!(
-                                    "Received packet from upstream with {} ancillary FDs: {:?}",
-                                    ancillary_accum.len(),
-                                    packet
+                        let upstream = match UnixStream::connect(&args.upstream) {
+                            Ok(u) => u,
+                            Err(e) => {
+                                eprintln!("Warning, error creating upstream connection, dropping client: {}", e);
+                                _ = proto::write_packet(
+                                    &mut &downstream,
+                                    &connection::display_error(
+                                        1,
+                                        protocol::wl_display::ERROR_IMPLEMENTATION,
+                                        &format!("couldn't connect to the compositor: {}", e),
+                                    ),
                                 );
-                            }
-
-                            // Tracking and manipulation
-                            match (packet.id, packet.opcode) {
-                                // Ack delete, hardcoded display
-                                (1, 1) => {
-                                    let mut cursor = Cursor::new(&packet.body);
-                                    let obj_id =
-                                        proto::read_arg_uint(
-                                            &mut cursor,
-                                        ).context("Error reading display delete obj id")?;
-                                    objects.lock().unwrap().remove(&obj_id);
-                                },
-                                _ => { },
-                            }
-                            if let Some(reg_id) = match &cache_reg_id {
-                                Some(r) => Some(*r),
-                                None => {
-                                    if let Some(ObjType::Registry) = objects.lock().unwrap().get(&packet.id) {
-                                        cache_reg_id = Some(packet.id);
-                                        Some(packet.id)
-                                    } else {
-                                        None
-                                    }
-                                },
-                            } {
-                                if reg_id == packet.id {
-                                    // global
-                                    if packet.opcode == 0 {
-                                        let mut cursor = Cursor::new(&packet.body);
-                                        let type_id =
-                                            proto::read_arg_uint(
-                                                &mut cursor,
-                                            ).context("Error reading global message type id")?;
-                                        let type_str =
-                                            proto::read_arg_string(
-                                                &mut cursor,
-                                            ).context("Error reading global message type string")?;
-                                        let version =
-                                            proto::read_arg_uint(
-                                                &mut cursor,
-                                            ).context("Error reading global message version")?;
-                                        if type_str.as_ref().map(|x| x.as_str()) == Some("xdg_wm_base") {
-                                            *xdgwmbase_type_id.lock().unwrap() = Some((type_id, version));
-                                        }
-                                    }
-                                }
-                            }
-
-                            // Forward messages
-                            proto::write_packet(
-                                &mut AncillaryWriter::new(&mut downstream, &mut ancillary_mem, &ancillary_accum),
-                                &packet,
-                            ).context("Error writing message")?;
-                            for fd in ancillary_accum.drain(..) {
-                                drop(unsafe {
-                                    OwnedFd::from_raw_fd(fd)
-                                });
-                            }
+                                continue;
+                            },
+                        };
+                        let conn = match Connection::new(client, downstream, upstream, args.debug.is_some()) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                eprintln!("Warning, error setting up connection, dropping client: {}", e);
+                                continue;
+                            },
+                        };
+                        let added =
+                            [(0, &conn.downstream.socket), (1, &conn.upstream.socket)]
+                                .into_iter()
+                                .try_for_each(|(side, socket)| epoll::add(
+                                    &epoll,
+                                    socket,
+                                    epoll::EventData::new_u64(conn_id * 2 + side),
+                                    epoll::EventFlags::IN | epoll::EventFlags::OUT | epoll::EventFlags::ET,
+                                ));
+                        if let Err(e) = added {
+                            // Closing the sockets removes any that were added
+                            eprintln!("Warning, error adding connection to epoll, dropping client: {}", e);
+                            continue;
                         }
-                        return Ok(());
-                    })() {
-                        Ok(_) => { },
-                        Err(e) => {
-                            eprintln!("Warning, server->client thread exiting with error: {}", e);
-                        },
+                        connections.insert(conn_id, conn);
                     }
+                    continue;
                 }
-            });
+                let id = token / 2;
+                let Some(conn) = connections.get_mut(&id) else {
+                    // Already closed by an earlier event in this batch
+                    continue;
+                };
+                let config = config.read().unwrap().clone();
+                match conn.pump(&config, &mut clipboard) {
+                    Ok(true) => { },
+                    Ok(false) => {
+                        if args.debug.is_some() {
+                            eprintln!("Connection {} closed", id);
+                        }
+                        connections.remove(&id);
+                        clipboard.connection_closed(id);
+                    },
+                    Err(e) => {
+                        eprintln!("Warning, closing connection {} with error: {}", id, e);
+                        connections.remove(&id);
+                        clipboard.connection_closed(id);
+                    },
+                }
+                deliver_clipboard(&mut connections, &mut clipboard, &config, args.debug.is_some());
+            }
         }
     }
 
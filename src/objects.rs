@@ -0,0 +1,113 @@
+use {
+    crate::{
+        proto::Arg,
+        protocol::{
+            self,
+            find_interface,
+            Interface,
+            Message,
+        },
+    },
+    std::collections::HashMap,
+};
+
+/// Ids at or above this are allocated by the server.
+pub const SERVER_ID_START: u32 = 0xff000000;
+
+#[derive(Clone, Copy)]
+pub struct Object {
+    pub interface: &'static Interface,
+    pub version: u32,
+}
+
+impl std::fmt::Debug for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}@v{}", self.interface.name, self.version);
+    }
+}
+
+/// All live objects on one connection, client and server allocated, by upstream
+/// id.
+pub struct Objects {
+    objects: HashMap<u32, Object>,
+    /// Objects that only exist on the client's side and are handled by filterway, by
+    /// downstream id
+    local: HashMap<u32, Object>,
+}
+
+impl Default for Objects {
+    fn default() -> Self {
+        let mut objects = HashMap::new();
+        objects.insert(1, Object {
+            interface: &protocol::wl_display::INTERFACE,
+            version: 1,
+        });
+        return Self {
+            objects: objects,
+            local: HashMap::new(),
+        };
+    }
+}
+
+impl Objects {
+    pub fn get(&self, id: u32) -> Option<Object> {
+        return self.objects.get(&id).cloned();
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.objects.remove(&id);
+    }
+
+    pub fn insert(&mut self, id: u32, object: Object) {
+        self.objects.insert(id, object);
+    }
+
+    pub fn get_local(&self, id: u32) -> Option<Object> {
+        return self.local.get(&id).cloned();
+    }
+
+    pub fn insert_local(&mut self, id: u32, object: Object) {
+        self.local.insert(id, object);
+    }
+
+    pub fn remove_local(&mut self, id: u32) {
+        self.local.remove(&id);
+    }
+
+    /// Record objects created by a message (request or event) sent to `parent`.
+    /// Server-allocated objects are also forgotten here when destroyed, since the
+    /// server doesn't send `delete_id` for them. Returns the interface names of any
+    /// new objects that couldn't be tracked.
+    pub fn track(&mut self, id: u32, parent: Object, message: &Message, args: &[Arg]) -> Vec<String> {
+        let mut untracked = vec![];
+        for (spec, arg) in message.args.iter().zip(args) {
+            match arg {
+                Arg::NewId(new_id) => {
+                    let Some(interface) = spec.interface.and_then(find_interface) else {
+                        untracked.push(spec.interface.unwrap_or_default().to_string());
+                        continue;
+                    };
+                    self.objects.insert(*new_id, Object {
+                        interface: interface,
+                        version: parent.version,
+                    });
+                },
+                Arg::UntypedNewId { interface, version, id: new_id } => {
+                    let Some(found) = interface.as_deref().and_then(find_interface) else {
+                        untracked.push(interface.clone().unwrap_or_default());
+                        continue;
+                    };
+                    self.objects.insert(*new_id, Object {
+                        interface: found,
+                        version: *version,
+                    });
+                },
+                _ => { },
+            }
+        }
+        if message.destructor && id >= SERVER_ID_START {
+            self.objects.remove(&id);
+        }
+        return untracked;
+    }
+}
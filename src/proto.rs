@@ -1,7 +1,16 @@
+use std::{
+    collections::VecDeque,
+    os::fd::RawFd,
+};
+
+/// Read exactly `size` bytes. Lengths come from untrusted messages so this only
+/// allocates for data that's actually there.
 fn read(reader: &mut impl std::io::Read, size: usize) -> std::io::Result<Vec<u8>> {
     let mut out = vec![];
-    out.resize(size, 0u8);
-    reader.read_exact(&mut out)?;
+    std::io::Read::read_to_end(&mut std::io::Read::take(reader, size as u64), &mut out)?;
+    if out.len() != size {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
     return Ok(out);
 }
 
@@ -15,83 +24,345 @@ pub struct Packet {
 // header word1 + word2
 const BODY_SIZE_ADJ: i64 = 8;
 
-pub fn read_packet(serial: &mut impl std::io::Read) -> Result<Option<Packet>, &'static str> {
-    let header_word1 = match read(serial, 4) {
-        Ok(x) => x,
-        Err(e) => {
-            match e.kind() {
-                std::io::ErrorKind::UnexpectedEof |
-                std::io::ErrorKind::BrokenPipe |
-                std::io::ErrorKind::NotConnected |
-                std::io::ErrorKind::ConnectionAborted |
-                std::io::ErrorKind::ConnectionRefused |
-                std::io::ErrorKind::ConnectionReset => {
-                    return Ok(None);
-                },
-                _ => {
-                    eprintln!("kind {:?}", e.kind());
-                    return Err("header word 1");
-                },
-            }
-        },
-    };
-    let header_word2 = read(serial, 4).map_err(|_| "header word 2")?;
-    let message_size = u16::from_ne_bytes(header_word2[2usize .. 2usize + 2usize].try_into().unwrap());
-    let body = read(serial, (message_size as i64 - BODY_SIZE_ADJ) as usize).map_err(|_| "body")?;
-    let opcode = u16::from_ne_bytes(header_word2[0usize .. 0usize + 2usize].try_into().unwrap());
-    let id = u32::from_ne_bytes(header_word1[0usize .. 0usize + 4usize].try_into().unwrap());
-    return Ok(Some(Packet {
-        id: id,
-        opcode: opcode,
-        body: body,
-    }));
+pub const HEADER_SIZE: usize = 8;
+
+/// Check the size from a message header. Messages are made of 32 bit words, so
+/// the size must be a whole number of them and include the header.
+pub fn validate_size(size: usize) -> Result<(), &'static str> {
+    if size < HEADER_SIZE {
+        return Err("message size smaller than header");
+    }
+    if !size.is_multiple_of(4) {
+        return Err("message size not a multiple of 4");
+    }
+    return Ok(());
+}
+
+/// Parse a message header, returning the object id, opcode, and size of the whole
+/// message including the header.
+///
+/// Everything on the wire is in host byte order. The second header word is a
+/// `u32` with the size in the high 16 bits and the opcode in the low 16, so the
+/// byte positions of each half depend on endianness.
+pub fn read_header(header: &[u8; HEADER_SIZE]) -> (u32, u16, usize) {
+    let id = u32::from_ne_bytes(header[0 .. 4].try_into().unwrap());
+    let header_word2 = u32::from_ne_bytes(header[4 .. 8].try_into().unwrap());
+    let opcode = (header_word2 & 0xffff) as u16;
+    let message_size = header_word2 >> 16;
+    return (id, opcode, message_size as usize);
 }
 
 pub fn write_packet(serial: &mut impl std::io::Write, data: &Packet) -> Result<(), &'static str> {
-    let mut header_word2 = std::vec::Vec::new();
-    header_word2.resize(4usize, 0u8);
-    let message_size = (data.body.len() as i64 + BODY_SIZE_ADJ) as u16;
-    header_word2[2usize .. 2usize + 2usize].copy_from_slice(&message_size.to_le_bytes());
-    header_word2[0usize .. 0usize + 2usize].copy_from_slice(&data.opcode.to_le_bytes());
-    let mut header_word1 = std::vec::Vec::new();
-    header_word1.resize(4usize, 0u8);
-    header_word1[0usize .. 0usize + 4usize].copy_from_slice(&data.id.to_le_bytes());
-    serial.write_all(&header_word1).map_err(|_| "header word 1")?;
-    serial.write_all(&header_word2).map_err(|_| "header word 2")?;
+    let message_size = data.body.len() as i64 + BODY_SIZE_ADJ;
+    if message_size > u16::MAX as i64 {
+        return Err("message too large");
+    }
+    let header_word2 = ((message_size as u32) << 16) | data.opcode as u32;
+    serial.write_all(&data.id.to_ne_bytes()).map_err(|_| "header word 1")?;
+    serial.write_all(&header_word2.to_ne_bytes()).map_err(|_| "header word 2")?;
     serial.write_all(&data.body).map_err(|_| "body")?;
     return Ok(());
 }
 
-pub fn read_arg_uint(serial: &mut impl std::io::Read) -> Result<u32, &str> {
+pub fn read_arg_uint(serial: &mut impl std::io::Read) -> Result<u32, &'static str> {
     let header = read(serial, 4).map_err(|_| "uint")?;
     return Ok(u32::from_ne_bytes(header[..].try_into().unwrap()));
 }
 
-pub fn write_arg_uint(serial: &mut impl std::io::Write, data: u32) -> Result<(), &str> {
-    match serial.write_all(&mut data.to_ne_bytes()) {
+pub fn write_arg_uint(serial: &mut impl std::io::Write, data: u32) -> Result<(), &'static str> {
+    match serial.write_all(&data.to_ne_bytes()) {
         Ok(_) => (),
-        Err(_) => return Err("string length"),
+        Err(_) => return Err("uint"),
     };
     return Ok(());
 }
 
-pub fn read_arg_string(serial: &mut impl std::io::Read) -> Result<Option<String>, &str> {
+pub fn read_arg_string(serial: &mut impl std::io::Read) -> Result<Option<String>, &'static str> {
     let header = read(serial, 4).map_err(|_| "null terminated string length")?;
     let null_term_len = u32::from_ne_bytes(header[..].try_into().unwrap());
     if null_term_len == 0 {
         return Ok(None);
     }
-    let mut body = read(serial, null_term_len.next_multiple_of(4) as usize).map_err(|_| "string body")?;
-    body.truncate(null_term_len as usize - 1);
+    let mut body =
+        read(
+            serial,
+            (null_term_len as usize).next_multiple_of(4),
+        ).map_err(|_| "string length exceeds message size")?;
+    body.truncate(null_term_len as usize);
+    if body.pop() != Some(0) {
+        return Err("string not NUL terminated");
+    }
     return Ok(Some(String::from_utf8(body).map_err(|_| "bad utf-8")?));
 }
 
-pub fn write_arg_string(serial: &mut impl std::io::Write, data: String) -> Result<(), &str> {
+pub fn write_arg_string(serial: &mut impl std::io::Write, data: String) -> Result<(), &'static str> {
     let mut buf = data.into_bytes();
     buf.push(0);
     let null_term_len = buf.len();
     buf.resize(buf.len().next_multiple_of(4), 0u8);
-    serial.write_all(&mut (null_term_len as u32).to_ne_bytes()).map_err(|_| "null terminated string length")?;
-    serial.write_all(&mut buf).map_err(|_| "string body")?;
+    serial.write_all(&(null_term_len as u32).to_ne_bytes()).map_err(|_| "null terminated string length")?;
+    serial.write_all(&buf).map_err(|_| "string body")?;
+    return Ok(());
+}
+
+pub fn read_arg_int(serial: &mut impl std::io::Read) -> Result<i32, &'static str> {
+    let header = read(serial, 4).map_err(|_| "int")?;
+    return Ok(i32::from_ne_bytes(header[..].try_into().unwrap()));
+}
+
+pub fn write_arg_int(serial: &mut impl std::io::Write, data: i32) -> Result<(), &'static str> {
+    serial.write_all(&data.to_ne_bytes()).map_err(|_| "int")?;
     return Ok(());
 }
+
+pub fn read_arg_array(serial: &mut impl std::io::Read) -> Result<Vec<u8>, &'static str> {
+    let header = read(serial, 4).map_err(|_| "array length")?;
+    let len = u32::from_ne_bytes(header[..].try_into().unwrap());
+    let mut body =
+        read(serial, (len as usize).next_multiple_of(4)).map_err(|_| "array length exceeds message size")?;
+    body.truncate(len as usize);
+    return Ok(body);
+}
+
+pub fn write_arg_array(serial: &mut impl std::io::Write, data: &[u8]) -> Result<(), &'static str> {
+    serial.write_all(&(data.len() as u32).to_ne_bytes()).map_err(|_| "array length")?;
+    serial.write_all(data).map_err(|_| "array body")?;
+    let pad = data.len().next_multiple_of(4) - data.len();
+    serial.write_all(&[0u8; 4][..pad]).map_err(|_| "array padding")?;
+    return Ok(());
+}
+
+/// Argument wire types, as in the protocol XML `type` attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgType {
+    Int,
+    Uint,
+    /// Signed 24.8 fixed point
+    Fixed,
+    String,
+    Object,
+    /// `new_id` with an interface specified in the protocol
+    NewId,
+    /// `new_id` without an interface in the protocol (only `wl_registry.bind`), sent
+    /// on the wire as an interface name string, version, then id
+    UntypedNewId,
+    Array,
+    /// Sent out of band as ancillary data, takes no space in the body
+    Fd,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Arg {
+    Int(i32),
+    Uint(u32),
+    /// Raw signed 24.8 fixed point value
+    Fixed(i32),
+    /// Strings may be null (`None`) on the wire
+    String(Option<String>),
+    /// Object id, 0 for null
+    Object(u32),
+    NewId(u32),
+    UntypedNewId {
+        interface: Option<String>,
+        version: u32,
+        id: u32,
+    },
+    Array(Vec<u8>),
+    Fd(RawFd),
+}
+
+impl Arg {
+    pub fn type_(&self) -> ArgType {
+        match self {
+            Arg::Int(_) => return ArgType::Int,
+            Arg::Uint(_) => return ArgType::Uint,
+            Arg::Fixed(_) => return ArgType::Fixed,
+            Arg::String(_) => return ArgType::String,
+            Arg::Object(_) => return ArgType::Object,
+            Arg::NewId(_) => return ArgType::NewId,
+            Arg::UntypedNewId { .. } => return ArgType::UntypedNewId,
+            Arg::Array(_) => return ArgType::Array,
+            Arg::Fd(_) => return ArgType::Fd,
+        }
+    }
+}
+
+/// Parse a message body according to `signature`. File descriptor arguments are
+/// taken from the front of `fds`.
+pub fn decode_args(body: &[u8], signature: &[ArgType], fds: &mut VecDeque<RawFd>) -> Result<Vec<Arg>, &'static str> {
+    let mut cursor = body;
+    let mut out = Vec::with_capacity(signature.len());
+    for type_ in signature {
+        out.push(match type_ {
+            ArgType::Int => Arg::Int(read_arg_int(&mut cursor)?),
+            ArgType::Uint => Arg::Uint(read_arg_uint(&mut cursor)?),
+            ArgType::Fixed => Arg::Fixed(read_arg_int(&mut cursor)?),
+            ArgType::String => Arg::String(read_arg_string(&mut cursor)?),
+            ArgType::Object => Arg::Object(read_arg_uint(&mut cursor)?),
+            ArgType::NewId => Arg::NewId(read_arg_uint(&mut cursor)?),
+            ArgType::UntypedNewId => Arg::UntypedNewId {
+                interface: read_arg_string(&mut cursor)?,
+                version: read_arg_uint(&mut cursor)?,
+                id: read_arg_uint(&mut cursor)?,
+            },
+            ArgType::Array => Arg::Array(read_arg_array(&mut cursor)?),
+            ArgType::Fd => Arg::Fd(fds.pop_front().ok_or("missing fd")?),
+        });
+    }
+    if !cursor.is_empty() {
+        return Err("trailing data after arguments");
+    }
+    return Ok(out);
+}
+
+/// Serialize arguments into a message body. File descriptor arguments are appended
+/// to `fds` in order.
+pub fn encode_args(args: &[Arg], body: &mut Vec<u8>, fds: &mut Vec<RawFd>) -> Result<(), &'static str> {
+    for arg in args {
+        match arg {
+            Arg::Int(v) => write_arg_int(body, *v)?,
+            Arg::Uint(v) => write_arg_uint(body, *v)?,
+            Arg::Fixed(v) => write_arg_int(body, *v)?,
+            Arg::String(v) => match v {
+                Some(v) => write_arg_string(body, v.clone())?,
+                None => write_arg_uint(body, 0)?,
+            },
+            Arg::Object(v) => write_arg_uint(body, *v)?,
+            Arg::NewId(v) => write_arg_uint(body, *v)?,
+            Arg::UntypedNewId { interface, version, id } => {
+                match interface {
+                    Some(v) => write_arg_string(body, v.clone())?,
+                    None => write_arg_uint(body, 0)?,
+                }
+                write_arg_uint(body, *version)?;
+                write_arg_uint(body, *id)?;
+            },
+            Arg::Array(v) => write_arg_array(body, v)?,
+            Arg::Fd(v) => fds.push(*v),
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(args: Vec<Arg>) {
+        let signature = args.iter().map(|a| a.type_()).collect::<Vec<_>>();
+        let mut body = vec![];
+        let mut fds = vec![];
+        encode_args(&args, &mut body, &mut fds).unwrap();
+        assert_eq!(body.len() % 4, 0);
+        let decoded = decode_args(&body, &signature, &mut VecDeque::from(fds)).unwrap();
+        assert_eq!(decoded, args);
+    }
+
+    #[test]
+    fn round_trip_numbers() {
+        round_trip(vec![Arg::Int(0), Arg::Int(-1), Arg::Int(i32::MIN), Arg::Int(i32::MAX)]);
+        round_trip(vec![Arg::Uint(0), Arg::Uint(0x01020304), Arg::Uint(u32::MAX)]);
+        round_trip(vec![Arg::Fixed(-256), Arg::Fixed(0x7fffff00)]);
+        round_trip(vec![Arg::Object(0), Arg::Object(0xff000001), Arg::NewId(3)]);
+    }
+
+    #[test]
+    fn round_trip_strings() {
+        round_trip(vec![Arg::String(None)]);
+        round_trip(vec![Arg::String(Some("".to_string()))]);
+        for len in 1 .. 9 {
+            round_trip(vec![Arg::String(Some("x".repeat(len))), Arg::Uint(7)]);
+        }
+        round_trip(vec![Arg::String(Some("ünïcødé".to_string()))]);
+    }
+
+    #[test]
+    fn round_trip_arrays() {
+        for len in 0 .. 9 {
+            round_trip(vec![Arg::Array((0 .. len as u8).collect()), Arg::Int(-7)]);
+        }
+    }
+
+    #[test]
+    fn round_trip_untyped_new_id() {
+        round_trip(vec![Arg::Uint(4), Arg::UntypedNewId {
+            interface: Some("wl_compositor".to_string()),
+            version: 6,
+            id: 5,
+        }]);
+    }
+
+    #[test]
+    fn round_trip_fds() {
+        round_trip(vec![Arg::NewId(9), Arg::Fd(11), Arg::Int(4096), Arg::Fd(12)]);
+    }
+
+    #[test]
+    fn native_byte_order() {
+        let mut body = vec![];
+        encode_args(&[Arg::Uint(0x01020304), Arg::Int(-2)], &mut body, &mut vec![]).unwrap();
+        assert_eq!(body[0 .. 4], 0x01020304u32.to_ne_bytes());
+        assert_eq!(body[4 .. 8], (-2i32).to_ne_bytes());
+        let mut body = vec![];
+        encode_args(&[Arg::String(Some("ab".to_string()))], &mut body, &mut vec![]).unwrap();
+        assert_eq!(body[0 .. 4], 3u32.to_ne_bytes());
+        assert_eq!(&body[4 ..], b"ab\0\0");
+    }
+
+    #[test]
+    fn reject_malformed() {
+        let decode = |body: &[u8], signature: &[ArgType]| decode_args(body, signature, &mut VecDeque::new());
+
+        // Length past the end of the body
+        let mut body = vec![];
+        write_arg_uint(&mut body, 0xffffffff).unwrap();
+        assert!(decode(&body, &[ArgType::String]).is_err());
+        assert!(decode(&body, &[ArgType::Array]).is_err());
+
+        // Missing NUL
+        let mut body = vec![];
+        write_arg_uint(&mut body, 4).unwrap();
+        body.extend_from_slice(b"abcd");
+        assert!(decode(&body, &[ArgType::String]).is_err());
+
+        // Truncated
+        assert!(decode(&[1, 2], &[ArgType::Uint]).is_err());
+        assert!(decode(&[0; 8], &[ArgType::Uint]).is_err());
+        assert!(validate_size(4).is_err());
+        assert!(validate_size(14).is_err());
+        assert!(validate_size(12).is_ok());
+    }
+
+    #[test]
+    fn round_trip_header() {
+        let packet = Packet {
+            id: 0xff000003,
+            opcode: 0x0102,
+            body: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let mut wire = vec![];
+        write_packet(&mut wire, &packet).unwrap();
+        assert_eq!(wire.len(), 16);
+
+        // The second word is a single host order u32: size << 16 | opcode
+        assert_eq!(wire[0 .. 4], 0xff000003u32.to_ne_bytes());
+        assert_eq!(wire[4 .. 8], ((16u32 << 16) | 0x0102).to_ne_bytes());
+        let (id, opcode, size) = read_header(wire[.. HEADER_SIZE].try_into().unwrap());
+        assert_eq!((id, opcode, size), (packet.id, packet.opcode, 16));
+        assert_eq!(wire[HEADER_SIZE ..], packet.body);
+    }
+
+    #[test]
+    fn reject_oversized_packet() {
+        let mut packet = Packet {
+            id: 3,
+            opcode: 0,
+            body: vec![0; u16::MAX as usize - HEADER_SIZE - 3],
+        };
+        assert!(write_packet(&mut vec![], &packet).is_ok());
+        packet.body.extend_from_slice(&[0; 4]);
+        let mut wire = vec![];
+        assert!(write_packet(&mut wire, &packet).is_err());
+        assert!(wire.is_empty());
+    }
+}
@@ -0,0 +1,46 @@
+//! Interface, message, and opcode tables generated by `build.rs` from the protocol
+//! XML in `protocols/`.
+//!
+//! Each interface gets a module named after it containing `REQ_*` and `EVT_*`
+//! opcode constants, enum values as `ENUM_ENTRY` constants, and the `INTERFACE`
+//! description.
+use crate::proto::ArgType;
+
+#[derive(Debug)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub type_: ArgType,
+    /// The interface of an `object` or `new_id` argument, if the protocol specifies
+    /// it
+    pub interface: Option<&'static str>,
+    pub nullable: bool,
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub name: &'static str,
+    pub since: u32,
+    pub destructor: bool,
+    pub args: &'static [ArgSpec],
+}
+
+impl Message {
+    pub fn signature(&self) -> Vec<ArgType> {
+        return self.args.iter().map(|a| a.type_).collect();
+    }
+}
+
+#[derive(Debug)]
+pub struct Interface {
+    pub name: &'static str,
+    /// Highest version described by the bundled protocol XML
+    pub version: u32,
+    pub requests: &'static [Message],
+    pub events: &'static [Message],
+}
+
+pub fn find_interface(name: &str) -> Option<&'static Interface> {
+    return INTERFACES.binary_search_by(|i| i.name.cmp(name)).ok().map(|i| INTERFACES[i]);
+}
+
+include!(concat!(env!("OUT_DIR"), "/protocol.rs"));
@@ -0,0 +1,505 @@
+use {
+    crate::{
+        proto::{
+            self,
+            Packet,
+        },
+        Errorize,
+    },
+    rustix::fd::{
+        AsRawFd,
+        FromRawFd,
+        OwnedFd,
+        RawFd,
+    },
+    std::{
+        collections::VecDeque,
+        io::{
+            ErrorKind,
+            IoSlice,
+            IoSliceMut,
+        },
+        os::unix::net::{
+            AncillaryData,
+            SocketAncillary,
+            UnixStream,
+        },
+    },
+};
+
+/// Big enough for the largest possible message (the size field is 16 bits)
+const READ_CAPACITY: usize = 1 << 16;
+
+/// The most fds the kernel passes in one `SCM_RIGHTS` message (`SCM_MAX_FD`)
+const MAX_FDS_IN: usize = 253;
+
+/// libwayland only reads this many fds at a time, so never send more in one go
+const MAX_FDS_OUT: usize = 28;
+
+/// Stop reading from one side of a connection while more than this is waiting to
+/// be sent to the other side, so a peer that stops reading can't make filterway
+/// buffer without limit.
+pub const MAX_WRITE_BACKLOG: usize = 4 * READ_CAPACITY;
+
+const fn ancillary_size(fds: usize) -> usize {
+    return unsafe {
+        libc::CMSG_SPACE((fds * size_of::<RawFd>()) as u32)
+    } as usize;
+}
+
+/// Fixed size circular buffer of received bytes.
+struct RingBuffer {
+    data: Box<[u8]>,
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> Self {
+        return Self {
+            data: vec![0u8; capacity].into_boxed_slice(),
+            start: 0,
+            len: 0,
+        };
+    }
+
+    fn is_full(&self) -> bool {
+        return self.len == self.data.len();
+    }
+
+    /// The unused part of the buffer, as up to two slices (if it wraps around)
+    fn free(&mut self) -> [&mut [u8]; 2] {
+        let capacity = self.data.len();
+        let end = (self.start + self.len) % capacity;
+        if end < self.start || self.len == capacity {
+            let (_, tail) = self.data.split_at_mut(end);
+            return [&mut tail[.. self.start - end], &mut []];
+        }
+        let (head, tail) = self.data.split_at_mut(end);
+        return [tail, &mut head[.. self.start]];
+    }
+
+    /// Mark `n` bytes written to `free` as used
+    fn commit(&mut self, n: usize) {
+        self.len += n;
+    }
+
+    /// Copy out the first `out.len()` bytes
+    fn peek(&self, out: &mut [u8]) {
+        self.peek_at(0, out);
+    }
+
+    /// Copy out `out.len()` bytes starting `skip` bytes in
+    fn peek_at(&self, skip: usize, out: &mut [u8]) {
+        let capacity = self.data.len();
+        let start = (self.start + skip) % capacity;
+        let len = out.len();
+        let first = len.min(capacity - start);
+        out[.. first].copy_from_slice(&self.data[start .. start + first]);
+        out[first..].copy_from_slice(&self.data[.. len - first]);
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start = (self.start + n) % self.data.len();
+        self.len -= n;
+    }
+}
+
+pub enum ReadStatus {
+    /// Everything available was read
+    Drained,
+    /// The read buffer is full; take messages out then read again
+    Full,
+    /// The peer closed the connection
+    Closed,
+}
+
+/// One end of a proxied connection. Reads and writes never block; incoming bytes
+/// and fds are buffered until a whole message is available, and outgoing messages
+/// are buffered until the socket accepts them.
+pub struct Stream {
+    pub socket: UnixStream,
+    read_buf: RingBuffer,
+    /// Stream offset of the start of `read_buf`
+    read_offset: usize,
+    /// Received fds not yet claimed by a message, with the stream offset of the start
+    /// of the read that returned them. The kernel doesn't say which bytes fds belong
+    /// to, only that they arrive no later than their message, so they're matched up
+    /// using message signatures.
+    read_fds: VecDeque<(usize, OwnedFd)>,
+    write_buf: Vec<u8>,
+    /// Fds of queued messages, with the offset in `write_buf` of the message they
+    /// belong to
+    write_fds: VecDeque<(usize, Vec<OwnedFd>)>,
+}
+
+impl Stream {
+    pub fn new(socket: UnixStream) -> Result<Self, String> {
+        socket.set_nonblocking(true).context("Error making socket non-blocking")?;
+        return Ok(Self {
+            socket: socket,
+            read_buf: RingBuffer::new(READ_CAPACITY),
+            read_offset: 0,
+            read_fds: VecDeque::new(),
+            write_buf: vec![],
+            write_fds: VecDeque::new(),
+        });
+    }
+
+    /// Read everything currently available, or until the read buffer is full. Each
+    /// `recvmsg` takes as much as fits.
+    pub fn fill(&mut self) -> Result<ReadStatus, String> {
+        let mut ancillary_mem = [0u8; ancillary_size(MAX_FDS_IN)];
+        loop {
+            if self.read_buf.is_full() {
+                return Ok(ReadStatus::Full);
+            }
+            let offset = self.read_offset + self.read_buf.len;
+            let mut ancillary = SocketAncillary::new(&mut ancillary_mem);
+            let [free1, free2] = self.read_buf.free();
+            let res =
+                self
+                    .socket
+                    .recv_vectored_with_ancillary(
+                        &mut [IoSliceMut::new(free1), IoSliceMut::new(free2)],
+                        &mut ancillary,
+                    );
+            let mut fds = vec![];
+            for m in ancillary.messages() {
+                let Ok(AncillaryData::ScmRights(m)) = m else {
+                    continue;
+                };
+                fds.extend(m.map(|fd| unsafe {
+                    OwnedFd::from_raw_fd(fd)
+                }));
+            }
+            if ancillary.truncated() {
+                // The kernel drops fds that don't fit, so the stream can't be resynced; the
+                // ones that did fit are closed on return.
+                return Err(format!("Received too many fds at once, got {} before truncation", fds.len()));
+            }
+            self.read_fds.extend(fds.into_iter().map(|fd| (offset, fd)));
+            match res {
+                Ok(0) => return Ok(ReadStatus::Closed),
+                Ok(n) => self.read_buf.commit(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadStatus::Drained),
+                Err(e) if e.kind() == ErrorKind::Interrupted => { },
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => return Ok(ReadStatus::Closed),
+                Err(e) => return Err(format!("Error reading from socket: {}", e)),
+            }
+        }
+    }
+
+    /// Take the next complete message from the read buffer, along with its fds.
+    /// `fd_count` says how many fds a message carries, from its signature. If the
+    /// message is unknown (`None`) it gets the pending fds from reads up to the one
+    /// with its first byte, less those that known messages after it in the buffer
+    /// need. Fds are sent with the first byte of a `sendmsg`, so fds from later
+    /// reads can only belong to later messages, but messages sent together share
+    /// the fds of one read.
+    pub fn next_packet(
+        &mut self,
+        fd_count: impl Fn(&Packet) -> Option<usize>,
+    ) -> Result<Option<(Packet, Vec<OwnedFd>)>, String> {
+        if self.read_buf.len < proto::HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; proto::HEADER_SIZE];
+        self.read_buf.peek(&mut header);
+        let (id, opcode, size) = proto::read_header(&header);
+        proto::validate_size(size).map_err(|e| format!("Invalid message for object {}: {} ({})", id, e, size))?;
+        if self.read_buf.len < size {
+            return Ok(None);
+        }
+        let mut message = vec![0u8; size];
+        self.read_buf.peek(&mut message);
+        self.read_buf.consume(size);
+        let start = self.read_offset;
+        self.read_offset += size;
+        message.drain(.. proto::HEADER_SIZE);
+        let packet = Packet {
+            id: id,
+            opcode: opcode,
+            body: message,
+        };
+        let count = match fd_count(&packet) {
+            Some(count) => {
+                if count > self.read_fds.len() {
+                    return Err(
+                        format!(
+                            "Message {:?} needs {} fds but only {} were received",
+                            packet,
+                            count,
+                            self.read_fds.len()
+                        ),
+                    );
+                }
+                count
+            },
+            None => {
+                let pending = self.read_fds.iter().take_while(|(offset, _)| *offset <= start).count();
+                (0 ..= pending).rev().find(|skip| self.fds_fit_after(*skip, &fd_count)).unwrap_or(0)
+            },
+        };
+        return Ok(Some((packet, self.read_fds.drain(..count).map(|(_, fd)| fd).collect())));
+    }
+
+    /// Whether the complete messages in the read buffer can get their fds if the
+    /// first `skip` pending fds are taken first. Only looks up to the first unknown
+    /// message, and `fd_count` only gets the message header.
+    fn fds_fit_after(&self, skip: usize, fd_count: &impl Fn(&Packet) -> Option<usize>) -> bool {
+        let mut next_fd = skip;
+        let mut pos = 0;
+        while self.read_buf.len - pos >= proto::HEADER_SIZE {
+            let mut header = [0u8; proto::HEADER_SIZE];
+            self.read_buf.peek_at(pos, &mut header);
+            let (id, opcode, size) = proto::read_header(&header);
+            if proto::validate_size(size).is_err() || self.read_buf.len - pos < size {
+                break;
+            }
+            let Some(count) = fd_count(&Packet {
+                id: id,
+                opcode: opcode,
+                body: vec![],
+            }) else {
+                break;
+            };
+            let start = self.read_offset + pos;
+            let fds = self.read_fds.range(next_fd.min(self.read_fds.len())..);
+            if fds.len() < count || fds.take(count).any(|(offset, _)| *offset > start) {
+                return false;
+            }
+            next_fd += count;
+            pos += size;
+        }
+        return true;
+    }
+
+    /// Bytes queued but not yet accepted by the socket.
+    pub fn write_backlog(&self) -> usize {
+        return self.write_buf.len();
+    }
+
+    /// Add a message to the write buffer. Call `flush` to send it.
+    pub fn queue(&mut self, packet: &Packet, fds: Vec<OwnedFd>) -> Result<(), String> {
+        let offset = self.write_buf.len();
+        proto::write_packet(&mut self.write_buf, packet)
+            .map_err(|e| format!("Error queueing message for object {} opcode {}: {}", packet.id, packet.opcode, e))?;
+        if !fds.is_empty() {
+            self.write_fds.push_back((offset, fds));
+        }
+        return Ok(());
+    }
+
+    /// Send as much of the write buffer as the socket will take. A message's fds are
+    /// sent with the first byte of the message: each send stops short of the next
+    /// message with fds, so the send starting with it can carry them. If only part of
+    /// a send is accepted the rest is retried on the next call (after the socket
+    /// becomes writable again).
+    pub fn flush(&mut self) -> Result<(), String> {
+        let mut ancillary_mem = [0u8; ancillary_size(MAX_FDS_OUT)];
+        while !self.write_buf.is_empty() {
+            let mut ancillary = SocketAncillary::new(&mut ancillary_mem);
+            let mut end = self.write_buf.len();
+            let mut attached = false;
+            let mut pending_fds = self.write_fds.iter();
+            if let Some((offset, fds)) = pending_fds.next() {
+                if *offset == 0 {
+                    if !ancillary.add_fds(&fds.iter().map(|fd| fd.as_raw_fd()).collect::<Vec<_>>()) {
+                        return Err(format!("Too many fds ({}) to send with one message", fds.len()));
+                    }
+                    attached = true;
+                    if let Some((next_offset, _)) = pending_fds.next() {
+                        end = *next_offset;
+                    }
+                } else {
+                    end = *offset;
+                }
+            }
+            match self.socket.send_vectored_with_ancillary(&[IoSlice::new(&self.write_buf[..end])], &mut ancillary) {
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                    if attached {
+                        // The kernel takes the fds with the first byte sent; close our copies
+                        self.write_fds.pop_front();
+                    }
+                    for (offset, _) in &mut self.write_fds {
+                        *offset -= n;
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => { },
+                Err(e) => return Err(format!("Error writing to socket: {}", e)),
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::os::unix::net::SocketAncillary,
+    };
+
+    /// Send `data` in one `sendmsg`, with `fds` attached to its first byte.
+    fn send(socket: &UnixStream, data: &[u8], fds: &[RawFd]) {
+        let mut ancillary_mem = [0u8; ancillary_size(MAX_FDS_OUT)];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_mem);
+        assert!(ancillary.add_fds(fds));
+        let n = socket.send_vectored_with_ancillary(&[IoSlice::new(data)], &mut ancillary).unwrap();
+        assert_eq!(n, data.len());
+    }
+
+    fn inode(fd: &impl rustix::fd::AsFd) -> u64 {
+        return rustix::fs::fstat(fd).unwrap().st_ino;
+    }
+
+    fn message(id: u32, opcode: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        proto::write_packet(&mut out, &Packet {
+            id: id,
+            opcode: opcode,
+            body: body.to_vec(),
+        }).unwrap();
+        return out;
+    }
+
+    #[test]
+    fn unknown_message_fds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut stream = Stream::new(b).unwrap();
+        let (fd, _other) = UnixStream::pair().unwrap();
+
+        // An unknown message split across two `sendmsg`s, the second also carrying a
+        // known message with an fd
+        let unknown = message(5, 9, &[0; 8]);
+        let known = message(6, 1, &[]);
+        send(&a, &unknown[.. 8], &[]);
+        stream.fill().unwrap();
+        send(&a, &[&unknown[8 ..], &known[..]].concat(), &[fd.as_raw_fd()]);
+        stream.fill().unwrap();
+        let fd_count = |p: &Packet| match p.id {
+            6 => Some(1),
+            _ => None,
+        };
+        let (packet, fds) = stream.next_packet(fd_count).unwrap().unwrap();
+        assert_eq!((packet.id, fds.len()), (5, 0));
+        let (packet, fds) = stream.next_packet(fd_count).unwrap().unwrap();
+        assert_eq!((packet.id, fds.len()), (6, 1));
+        assert!(stream.next_packet(fd_count).unwrap().is_none());
+
+        // Both in one `sendmsg`
+        send(&a, &[&unknown[..], &known[..]].concat(), &[fd.as_raw_fd()]);
+        stream.fill().unwrap();
+        let (packet, fds) = stream.next_packet(fd_count).unwrap().unwrap();
+        assert_eq!((packet.id, fds.len()), (5, 0));
+        let (packet, fds) = stream.next_packet(fd_count).unwrap().unwrap();
+        assert_eq!((packet.id, fds.len()), (6, 1));
+
+        // The unknown message keeps fds the known one doesn't need
+        send(&a, &[&unknown[..], &known[..]].concat(), &[fd.as_raw_fd(), fd.as_raw_fd()]);
+        stream.fill().unwrap();
+        let (packet, fds) = stream.next_packet(fd_count).unwrap().unwrap();
+        assert_eq!((packet.id, fds.len()), (5, 1));
+        let (packet, fds) = stream.next_packet(fd_count).unwrap().unwrap();
+        assert_eq!((packet.id, fds.len()), (6, 1));
+        assert!(stream.next_packet(fd_count).unwrap().is_none());
+    }
+
+    #[test]
+    fn ring_buffer_wraps() {
+        let mut buf = RingBuffer::new(8);
+        buf.commit(6);
+        buf.consume(6);
+        let [free1, free2] = buf.free();
+        assert_eq!((free1.len(), free2.len()), (2, 6));
+        free1.copy_from_slice(&[1, 2]);
+        free2[.. 3].copy_from_slice(&[3, 4, 5]);
+        buf.commit(5);
+        let mut out = [0u8; 5];
+        buf.peek(&mut out);
+        assert_eq!(out, [1, 2, 3, 4, 5]);
+        buf.consume(2);
+        let mut out = [0u8; 3];
+        buf.peek(&mut out);
+        assert_eq!(out, [3, 4, 5]);
+        assert_eq!(buf.free()[0].len(), 5);
+    }
+
+    #[test]
+    fn message_across_wrap_point() {
+        let (a, b) = UnixStream::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        let mut stream = Stream::new(b).unwrap();
+        let first = message(3, 0, &vec![7; READ_CAPACITY - 4096 - proto::HEADER_SIZE]);
+        let second = message(4, 2, &(0 .. 8192).map(|i| i as u8).collect::<Vec<_>>());
+        send(&a, &first, &[]);
+        stream.fill().unwrap();
+        let (packet, _) = stream.next_packet(|_| Some(0)).unwrap().unwrap();
+        assert_eq!(packet.id, 3);
+
+        // Starts 4096 bytes before the end of the buffer
+        send(&a, &second, &[]);
+        stream.fill().unwrap();
+        let (packet, _) = stream.next_packet(|_| Some(0)).unwrap().unwrap();
+        assert_eq!((packet.id, packet.opcode), (4, 2));
+        assert_eq!(packet.body, second[proto::HEADER_SIZE ..]);
+    }
+
+    #[test]
+    fn fds_stay_with_message_across_short_writes() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut writer = Stream::new(a).unwrap();
+        let mut reader = Stream::new(b).unwrap();
+        let (fd1, _other1) = UnixStream::pair().unwrap();
+        let (fd2, _other2) = UnixStream::pair().unwrap();
+
+        // More than the socket buffer holds, so sends come up short
+        let big = Packet {
+            id: 3,
+            opcode: 0,
+            body: vec![1; 60000],
+        };
+        let with_fds = |id: u32| Packet {
+            id: id,
+            opcode: 1,
+            body: vec![2; 8],
+        };
+        let mut expected = vec![];
+        for i in 0 .. 8 {
+            writer.queue(&big, vec![]).unwrap();
+            expected.push((3, vec![]));
+            if i == 3 {
+                writer.queue(&with_fds(5), vec![fd1.try_clone().unwrap().into()]).unwrap();
+                expected.push((5, vec![inode(&fd1)]));
+            }
+        }
+        writer
+            .queue(&with_fds(6), vec![fd1.try_clone().unwrap().into(), fd2.try_clone().unwrap().into()])
+            .unwrap();
+        expected.push((6, vec![inode(&fd1), inode(&fd2)]));
+        let fd_count = |p: &Packet| Some(if p.opcode == 1 {
+            (p.id - 4) as usize
+        } else {
+            0
+        });
+        let mut received = vec![];
+        let mut short_writes = 0;
+        while received.len() < expected.len() {
+            writer.flush().unwrap();
+            if writer.write_backlog() > 0 {
+                short_writes += 1;
+            }
+            reader.fill().unwrap();
+            // Fds sent late would make `next_packet` fail
+            while let Some((packet, fds)) = reader.next_packet(fd_count).unwrap() {
+                received.push((packet.id, fds.iter().map(inode).collect::<Vec<_>>()));
+            }
+        }
+        assert!(short_writes > 0);
+        assert_eq!(received, expected);
+        assert!(reader.read_fds.is_empty());
+    }
+}
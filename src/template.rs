@@ -0,0 +1,208 @@
+use {
+    crate::client::ClientInfo,
+    serde::{
+        Deserialize,
+        Deserializer,
+    },
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplatePart {
+    Text(String),
+    /// The value the client sent, before rewriting
+    Original,
+    Pid,
+    Uid,
+    Gid,
+    Exe,
+    /// Arguments joined with spaces
+    Cmdline,
+    Comm,
+    Cgroup,
+    ConnId,
+    Socket,
+    /// An environment variable of the filterway process
+    Env(String),
+}
+
+/// A string with `{name}` placeholders, filled in per client. `{{` and `}}` are
+/// literal braces, and `${...}` is left as is for regex replacements.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(pub Vec<TemplatePart>);
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                // Regex replacement group references like `${1}` pass through untouched
+                '$' if chars.peek() == Some(&'{') => {
+                    literal.push('$');
+                    loop {
+                        match chars.next() {
+                            Some(c) => {
+                                literal.push(c);
+                                if c == '}' {
+                                    break;
+                                }
+                            },
+                            None => return Err(format!("Unterminated group reference in template [{}]", text)),
+                        }
+                    }
+                },
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unterminated placeholder in template [{}]", text)),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(match name.as_str() {
+                        "original" => TemplatePart::Original,
+                        "pid" => TemplatePart::Pid,
+                        "uid" => TemplatePart::Uid,
+                        "gid" => TemplatePart::Gid,
+                        "exe" => TemplatePart::Exe,
+                        "cmdline" => TemplatePart::Cmdline,
+                        "comm" => TemplatePart::Comm,
+                        "cgroup" => TemplatePart::Cgroup,
+                        "conn_id" => TemplatePart::ConnId,
+                        "socket" => TemplatePart::Socket,
+                        _ => match name.strip_prefix("env:") {
+                            Some(var) => TemplatePart::Env(var.to_string()),
+                            None => return Err(format!("Unknown placeholder [{}] in template [{}]", name, text)),
+                        },
+                    });
+                },
+                '}' => return Err(format!("Unmatched `}}` in template [{}], use `}}}}` for a literal brace", text)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Text(literal));
+        }
+        return Ok(Template(parts));
+    }
+
+    /// Fill in placeholders. Unknown values (like the pid of a client connecting from
+    /// another pid namespace) become empty strings. If `escape_dollar` is set, `$` in
+    /// placeholder values are escaped for use as a regex replacement.
+    pub fn render(&self, original: Option<&str>, client: &ClientInfo, escape_dollar: bool) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            let value = match part {
+                TemplatePart::Text(t) => {
+                    out.push_str(t);
+                    continue;
+                },
+                TemplatePart::Original => original.unwrap_or_default().to_string(),
+                TemplatePart::Pid => client.pid.map(|v| v.to_string()).unwrap_or_default(),
+                TemplatePart::Uid => client.uid.map(|v| v.to_string()).unwrap_or_default(),
+                TemplatePart::Gid => client.gid.map(|v| v.to_string()).unwrap_or_default(),
+                TemplatePart::Exe => client.exe.clone().unwrap_or_default(),
+                TemplatePart::Cmdline => client.cmdline.as_ref().map(|c| c.join(" ")).unwrap_or_default(),
+                TemplatePart::Comm => client.comm.clone().unwrap_or_default(),
+                TemplatePart::Cgroup => client.cgroup.clone().unwrap_or_default(),
+                TemplatePart::ConnId => client.conn_id.to_string(),
+                TemplatePart::Socket => client.socket.clone(),
+                TemplatePart::Env(var) => std::env::var(var).unwrap_or_default(),
+            };
+            if escape_dollar {
+                out.push_str(&value.replace('$', "$$"));
+            } else {
+                out.push_str(&value);
+            }
+        }
+        return out;
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        return Template::parse(&text).map_err(serde::de::Error::custom);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::client::ClientInfo,
+    };
+
+    #[test]
+    fn parse_placeholders() {
+        assert_eq!(
+            Template::parse("{comm} ({pid}): {original}").unwrap(),
+            Template(
+                vec![
+                    TemplatePart::Comm,
+                    TemplatePart::Text(" (".to_string()),
+                    TemplatePart::Pid,
+                    TemplatePart::Text("): ".to_string()),
+                    TemplatePart::Original
+                ],
+            ),
+        );
+        assert_eq!(
+            Template::parse("{env:HOME}").unwrap(),
+            Template(vec![TemplatePart::Env("HOME".to_string())]),
+        );
+        assert_eq!(Template::parse("").unwrap(), Template(vec![]));
+    }
+
+    #[test]
+    fn parse_escapes() {
+        assert_eq!(
+            Template::parse("{{literal}} {conn_id}").unwrap(),
+            Template(vec![TemplatePart::Text("{literal} ".to_string()), TemplatePart::ConnId]),
+        );
+        assert_eq!(
+            Template::parse("${1}-{uid}-$2").unwrap(),
+            Template(
+                vec![
+                    TemplatePart::Text("${1}-".to_string()),
+                    TemplatePart::Uid,
+                    TemplatePart::Text("-$2".to_string())
+                ],
+            ),
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Template::parse("{pid").is_err());
+        assert!(Template::parse("${1").is_err());
+        assert!(Template::parse("pid}").is_err());
+        assert!(Template::parse("{nope}").is_err());
+    }
+
+    #[test]
+    fn render() {
+        let client = ClientInfo {
+            conn_id: 3,
+            pid: Some(42),
+            comm: Some("a$b".to_string()),
+            ..Default::default()
+        };
+        let template = Template::parse("{comm}/{pid}/{uid}/{conn_id}/{original}").unwrap();
+        assert_eq!(template.render(Some("x"), &client, false), "a$b/42//3/x");
+        assert_eq!(template.render(None, &client, true), "a$$b/42//3/");
+    }
+}